@@ -0,0 +1,247 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::anyhow;
+use log::info;
+use netlink_ng::nl_type::{Family, FAMILY_V4, FAMILY_V6};
+use netlink_ng::{Link, LinkKind};
+use netns_ng::Netns;
+
+use cni_core::error::{CniError, ErrorCode};
+use cni_core::prelude::CniResult;
+use cni_core::skel::CmdArgs;
+use cni_core::types::{Interface, Ip, MacAddr, Route, SuccessReply};
+use invoke::Exec;
+
+use crate::types::NetConf;
+use crate::{bridge_by_name, spoof_check_table, DEFAULT_BR_NAME};
+
+const IFF_UP: u32 = 0x1;
+
+/// A way the attachment can differ from `prevResult`.
+///
+/// Each has its own error code, so a health checker can tell them apart
+/// without parsing messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    BridgeMissing = 110,
+    BridgeDown = 111,
+    BridgeMac = 112,
+    ContainerIfMissing = 113,
+    ContainerMac = 114,
+    ContainerAddress = 115,
+    HostVethMissing = 116,
+    HostVethMaster = 117,
+    RouteMissing = 118,
+    SpoofCheckMissing = 119,
+}
+
+impl Mismatch {
+    fn msg(self) -> &'static str {
+        match self {
+            Mismatch::BridgeMissing => "bridge not found",
+            Mismatch::BridgeDown => "bridge is down",
+            Mismatch::BridgeMac => "bridge MAC address does not match",
+            Mismatch::ContainerIfMissing => "container interface not found",
+            Mismatch::ContainerMac => "container interface MAC address does not match",
+            Mismatch::ContainerAddress => "container interface address missing",
+            Mismatch::HostVethMissing => "host veth not found",
+            Mismatch::HostVethMaster => "host veth not attached to bridge",
+            Mismatch::RouteMissing => "route missing",
+            Mismatch::SpoofCheckMissing => "MAC spoof check rule missing",
+        }
+    }
+
+    pub fn error(self, details: impl Into<String>) -> anyhow::Error {
+        CniError::new(ErrorCode::Plugin(self as u32), self.msg(), details).into()
+    }
+}
+
+pub fn cmd_check(args: &CmdArgs, net_conf: NetConf, exec: &dyn Exec) -> CniResult<()> {
+    info!("cmd_args: {:?}", args);
+
+    let prev_result = net_conf.prev_result.clone().ok_or_else(|| {
+        CniError::new(
+            ErrorCode::InvalidNetworkConfig,
+            "required prevResult missing",
+            "",
+        )
+    })?;
+    let netns = Netns::get_from_path(args.netns.as_ref())?
+        .ok_or_else(|| anyhow!("netns {} not found", args.netns))?;
+
+    if !net_conf.ipam.plugin.is_empty() {
+        invoke::delegate_check(
+            &net_conf.ipam.plugin,
+            &args.stdin_data,
+            &prev_result,
+            exec,
+            net_conf.ipam_timeout(),
+        )?;
+    }
+
+    let br_name = net_conf.br_name.as_deref().unwrap_or(DEFAULT_BR_NAME);
+    let br = check_bridge(&prev_result, br_name)?;
+
+    let (cont_index, cont_if) = prev_result
+        .interfaces
+        .iter()
+        .enumerate()
+        .find(|(_, it)| {
+            it.name == args.if_name && it.sandbox.as_deref() == Some(Path::new(&args.netns))
+        })
+        .ok_or_else(|| {
+            Mismatch::ContainerIfMissing.error(format!(
+                "prevResult has no interface {} in {}",
+                args.if_name, args.netns
+            ))
+        })?;
+    let ips = prev_result
+        .ips
+        .iter()
+        .filter(|it| it.interface == Some(cont_index))
+        .collect::<Vec<_>>();
+
+    let current_ns = Netns::get()?;
+    netns_ng::exec_netns!(current_ns, &netns, result, || -> CniResult<Link> {
+        let cont_link = check_container_interface(cont_if)?;
+        check_addresses(&cont_link, &ips)?;
+        check_routes(&prev_result.routes)?;
+        Ok(cont_link)
+    });
+    let cont_link: Link = result?;
+
+    // The host side is the veth whose peer is the container interface
+    let peer_index = cont_link.attrs().parent_index;
+    let host_veth = prev_result
+        .interfaces
+        .iter()
+        .filter(|it| it.name != br_name && it.sandbox.as_deref().is_none_or(is_host))
+        .filter_map(|it| netlink_ng::link_by_name(&it.name).ok().flatten())
+        .find(|it| it.attrs().index == peer_index)
+        .ok_or_else(|| {
+            Mismatch::HostVethMissing.error(format!(
+                "no veth in prevResult is the peer of {}",
+                args.if_name
+            ))
+        })?;
+    if host_veth.attrs().master_index != br.attrs().index {
+        return Err(Mismatch::HostVethMaster.error(format!(
+            "{} is not attached to {}",
+            host_veth.attrs().name,
+            br_name
+        )));
+    }
+
+    if net_conf.mac_spoof_chk.unwrap_or_default() {
+        let table = spoof_check_table(&net_conf, args);
+        let iface = &host_veth.attrs().name;
+        let mac = cont_if
+            .mac
+            .as_ref()
+            .map(|it| it.to_string())
+            .unwrap_or_default();
+        if !ip::verify_spoof_check(&table, iface, &mac)? {
+            return Err(Mismatch::SpoofCheckMissing.error(format!(
+                "table bridge {} does not limit {} to {}",
+                table, iface, mac
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn is_host(sandbox: &Path) -> bool {
+    sandbox.as_os_str().is_empty()
+}
+
+fn check_bridge(prev_result: &SuccessReply, br_name: &str) -> CniResult<Link> {
+    let br = bridge_by_name(br_name)?
+        .ok_or_else(|| Mismatch::BridgeMissing.error(format!("no bridge named {}", br_name)))?;
+    if br.attrs().flags & IFF_UP == 0 {
+        return Err(Mismatch::BridgeDown.error(br_name));
+    }
+
+    let reported = prev_result.interfaces.iter().find(|it| it.name == br_name);
+    if let Some(Interface { mac: Some(mac), .. }) = reported {
+        check_mac(&br, mac, Mismatch::BridgeMac)?;
+    }
+    Ok(br)
+}
+
+// Called from inside the container netns.
+fn check_container_interface(cont_if: &Interface) -> CniResult<Link> {
+    let link = netlink_ng::link_by_name(&cont_if.name)?.ok_or_else(|| {
+        Mismatch::ContainerIfMissing.error(format!("no link named {}", cont_if.name))
+    })?;
+    if !matches!(link.link_kind, LinkKind::Veth(_)) {
+        return Err(Mismatch::ContainerIfMissing.error(format!("{} is not a veth", cont_if.name)));
+    }
+    if let Some(mac) = &cont_if.mac {
+        check_mac(&link, mac, Mismatch::ContainerMac)?;
+    }
+    Ok(link)
+}
+
+fn check_mac(link: &Link, expected: &MacAddr, mismatch: Mismatch) -> CniResult<()> {
+    let actual = link
+        .attrs()
+        .hardware_addr
+        .as_deref()
+        .map(MacAddr::try_from)
+        .transpose()?;
+    if actual.as_ref() != Some(expected) {
+        return Err(mismatch.error(format!(
+            "{} has {}, prevResult has {}",
+            link.attrs().name,
+            actual.map(|it| it.to_string()).unwrap_or_default(),
+            expected
+        )));
+    }
+    Ok(())
+}
+
+fn family(ip: &IpAddr) -> Family {
+    match ip {
+        IpAddr::V4(_) => FAMILY_V4,
+        IpAddr::V6(_) => FAMILY_V6,
+    }
+}
+
+// Called from inside the container netns.
+fn check_addresses(link: &Link, ips: &[&Ip]) -> CniResult<()> {
+    for ip in ips {
+        let addrs = netlink_ng::addr_list(link.as_index(), family(&ip.address.ip()))?;
+        if !addrs.iter().any(|it| it.ipnet == ip.address) {
+            return Err(Mismatch::ContainerAddress.error(format!(
+                "{} is not on {}",
+                ip.address,
+                link.attrs().name
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Called from inside the container netns.
+fn check_routes(routes: &[Route]) -> CniResult<()> {
+    for route in routes {
+        let family = family(&route.dst.ip());
+        let found = netlink_ng::route_list(None, family)?.iter().any(|it| {
+            // The kernel reports default routes without a destination
+            let dst_matches = match it.dst {
+                Some(dst) => dst == route.dst,
+                None => route.dst.prefix() == 0,
+            };
+            dst_matches && (route.gw.is_none() || it.gw == route.gw)
+        });
+        if !found {
+            return Err(Mismatch::RouteMissing.error(format!(
+                "no route to {} via {}",
+                route.dst,
+                route.gw.map(|it| it.to_string()).unwrap_or_default()
+            )));
+        }
+    }
+    Ok(())
+}
@@ -1,46 +1,108 @@
-use std::io::{Read, stdin, stdout, Write};
 use std::net::Ipv4Addr;
 
 use anyhow::{anyhow, bail};
 use ipnetwork::{IpNetwork, Ipv4Network};
 use log::info;
-use netlink_ng::{Link, LinkAttrs, LinkKind, TryAsLinkIndex};
 use netlink_ng::nl_type::{Bridge, Family, FAMILY_V4, FAMILY_V6};
+use netlink_ng::{Link, LinkAttrs, LinkKind, TryAsLinkIndex};
 use netns_ng::Netns;
 use serde::{Deserialize, Serialize};
 
-use cni_core::{logger, skel};
-use cni_core::error::is_already_exists_error;
+use cni_core::error::{is_already_exists_error, CniError, ErrorCode};
 use cni_core::prelude::CniResult;
-use cni_core::skel::CmdArgs;
-use cni_core::types::{ExecResult, Interface, MacAddr, Route};
+use cni_core::skel;
+use cni_core::skel::{CmdArgs, CniPlugin};
+use cni_core::types::{Interface, MacAddr, Route, SuccessReply};
+use cni_core::version::PluginInfo;
+use invoke::{Exec, RawExec};
 
-use crate::types::NetConf;
+use crate::types::{invalid_config, NetConf};
 
+mod check;
 mod types;
 
+const DEFAULT_BR_NAME: &str = "cni0";
+const DEFAULT_VLAN_ID: u16 = 1;
+
 fn main() {
-    let _ = logger::init("bridge.log");
+    let plugin = BridgePlugin {
+        exec: Box::new(RawExec::default()),
+    };
+    skel::plugin_main(plugin, PluginInfo::all());
+}
 
-    let res = skel::plugin_main(
-        |args| cmd_add(args),
-        |args| cmd_add(args),
-        |args| cmd_add(args),
-    );
-    info!("res: {:?}", res);
+struct BridgePlugin {
+    exec: Box<dyn Exec>,
+}
+
+impl CniPlugin for BridgePlugin {
+    const NAME: &'static str = "bridge";
+    type Config = NetConf;
+    type Output = SuccessReply;
+
+    fn add(&self, args: &CmdArgs, config: NetConf) -> CniResult<SuccessReply> {
+        cmd_add(args, config, self.exec.as_ref())
+    }
+
+    fn del(&self, args: &CmdArgs, config: NetConf) -> CniResult<()> {
+        cmd_del(args, config, self.exec.as_ref())
+    }
+
+    fn check(&self, args: &CmdArgs, config: NetConf) -> CniResult<()> {
+        check::cmd_check(args, config, self.exec.as_ref())
+    }
+
+    fn status(&self, args: &CmdArgs, config: NetConf) -> CniResult<()> {
+        cmd_status(args, &config, self.exec.as_ref())
+    }
+
+    // Releases the leases of attachments the runtime no longer knows about
+    fn gc(&self, args: &CmdArgs, config: NetConf) -> CniResult<()> {
+        if config.ipam.plugin.is_empty() {
+            return Ok(());
+        }
+        invoke::delegate_gc(
+            &config.ipam.plugin,
+            &args.stdin_data,
+            self.exec.as_ref(),
+            config.ipam_timeout(),
+        )
+    }
 }
 
-fn cmd_add(args: CmdArgs) -> CniResult<()> {
+fn cmd_add(args: &CmdArgs, mut net_conf: NetConf, exec: &dyn Exec) -> CniResult<SuccessReply> {
     info!("cmd_args: {:?}", args);
-    let mut stdin_data = Vec::new();
-    stdin().read_to_end(&mut stdin_data)?;
-    let mut net_conf: NetConf = serde_json::from_slice(&stdin_data)?;
     info!("net_config: {:#?}", net_conf);
 
     if net_conf.is_default_gw.unwrap_or_default() {
         net_conf.is_gw = Some(true);
     }
+    let hairpin_mode = net_conf.hairpin_mode.unwrap_or_default();
+    if hairpin_mode && net_conf.promisc_mode.unwrap_or_default() {
+        return Err(invalid_config(
+            "cannot set hairpin mode and promiscuous mode at the same time",
+            "",
+        ));
+    }
+
+    // A port is either an access port or a trunk port
+    let vlan_id = net_conf.vlan_id()?;
+    let vlans = net_conf.trunk_vlans()?;
+    if vlan_id != 0 && !vlans.is_empty() {
+        return Err(invalid_config(
+            "cannot set vlan and vlanTrunk at the same time",
+            "",
+        ));
+    }
+    // Same default as the Go plugin: VLAN 1 stays unless asked otherwise
+    let preserve_default_vlan = net_conf.preserve_default_vlan.unwrap_or(true);
+
     let (br_link, br_interface) = setup_bridge(&net_conf)?;
+    if vlan_id != 0 {
+        // Lets the bridge itself, and so a gateway address on it, take
+        // part in the access VLAN
+        netlink_ng::bridge_vlan_add(&br_link, vlan_id, false, false, true, false)?;
+    }
     let netns = Netns::get_from_path(args.netns.as_ref())?.ok_or(anyhow!("netns not found"))?;
 
     let current_ns = Netns::get()?;
@@ -50,26 +112,34 @@ fn cmd_add(args: CmdArgs) -> CniResult<()> {
         &br_link,
         &args.if_name,
         net_conf.mtu.unwrap_or(1500),
-        false,
-        false,
-        0,
-        vec![],
-        false,
+        hairpin_mode,
+        vlan_id,
+        vlans,
+        preserve_default_vlan,
         "",
     )?;
     info!("host_interface: {:?}", host_interface);
     info!("container_interface: {:?}", container_interface);
 
-    let mut bridge_result = ExecResult {
-        cni_version: Some("1.0.0".to_string()),
-        interfaces: Some(vec![br_interface, host_interface, container_interface]),
-        ips: None,
-        routes: None,
-        dns: None,
+    let mut bridge_result = SuccessReply {
+        cni_version: net_conf.cni_version.clone(),
+        interfaces: vec![
+            br_interface,
+            host_interface.clone(),
+            container_interface.clone(),
+        ],
+        ..Default::default()
     };
 
+    // Releases the lease again if anything below fails
+    let ipam = invoke::delegate_add_with_rollback(
+        &net_conf.ipam.plugin,
+        &args.stdin_data,
+        exec,
+        net_conf.ipam_timeout(),
+    )?;
     {
-        let mut ipam_result: ExecResult = invoke::delegate_add(&net_conf.ipam.plugin, &stdin_data)?;
+        let ipam_result = ipam.result().clone();
         bridge_result.ips = ipam_result.ips;
         bridge_result.routes = ipam_result.routes;
         bridge_result.dns = ipam_result.dns;
@@ -99,18 +169,92 @@ fn cmd_add(args: CmdArgs) -> CniResult<()> {
     }
     if net_conf.ip_masq.unwrap_or_default() {
         let chain_name = utils::format_chain_name(&net_conf.name, &args.container_id);
-        for ip in bridge_result.ips.as_deref().unwrap_or_default() {
+        for ip in &bridge_result.ips {
             ip::setup_ip_masq(&ip.address, &chain_name)?;
         }
     }
 
-    let _ = stdout().write_fmt(format_args!(
-        "{}",
-        serde_json::to_string_pretty(&bridge_result)?
-    ));
+    // Last, so no later step can fail and leave the table behind
+    if net_conf.mac_spoof_chk.unwrap_or_default() {
+        let mac = container_interface
+            .mac
+            .as_ref()
+            .ok_or(anyhow!("container veth mac not found"))?;
+        ip::setup_spoof_check(
+            &spoof_check_table(&net_conf, args),
+            &host_interface.name,
+            &mac.to_string(),
+        )?;
+    }
+
+    ipam.commit();
+    Ok(bridge_result)
+}
+
+// DEL can be called any number of times, also after the container and its
+// netns are gone, so whatever is already missing counts as cleaned up.
+fn cmd_del(args: &CmdArgs, net_conf: NetConf, exec: &dyn Exec) -> CniResult<()> {
+    info!("cmd_args: {:?}", args);
+
+    if net_conf.mac_spoof_chk.unwrap_or_default() {
+        ip::teardown_spoof_check(&spoof_check_table(&net_conf, args))?;
+    }
+
+    let netns = if args.netns.is_empty() {
+        None
+    } else {
+        Netns::get_from_path(args.netns.as_ref())?
+    };
+    let mut ips = match netns {
+        Some(netns) => {
+            // Deleting the container side takes the host peer with it
+            let current_ns = Netns::get()?;
+            netns_ng::exec_netns!(current_ns, &netns, result, || {
+                ip::del_link_by_name_addr(&args.if_name)
+            });
+            result?
+        }
+        None => {
+            info!("netns {:?} is gone, skipping its links", args.netns);
+            vec![]
+        }
+    };
+
+    if net_conf.ipam.plugin.is_empty() {
+        return Ok(());
+    }
+    invoke::delegate_del(
+        &net_conf.ipam.plugin,
+        &args.stdin_data,
+        true,
+        exec,
+        net_conf.ipam_timeout(),
+    )?;
+
+    if net_conf.ip_masq.unwrap_or_default() {
+        // Without the veth, e.g. on a repeated DEL or once the netns is gone,
+        // the addresses are only known from prevResult
+        if ips.is_empty() {
+            if let Some(prev_result) = &net_conf.prev_result {
+                ips = prev_result.ips.iter().map(|it| it.address).collect();
+            }
+        }
+        let chain_name = utils::format_chain_name(&net_conf.name, &args.container_id);
+        for ip in &ips {
+            ip::teardown_ip_masq(ip, &chain_name)?;
+        }
+    }
     Ok(())
 }
 
+/// The nftables table holding the MAC spoof check of one attachment.
+fn spoof_check_table(net_conf: &NetConf, args: &CmdArgs) -> String {
+    utils::format_chain_name(
+        &net_conf.name,
+        &format!("{}-{}", args.container_id, args.if_name),
+    )
+}
+
 fn enable_ip_forward(family: Family) -> CniResult<()> {
     match family {
         FAMILY_V4 => ip::enable_ipv4_forward(),
@@ -158,11 +302,11 @@ fn ensure_addr(br: &Link, ip: &IpNetwork, force_address: bool) -> CniResult<()>
     Ok(())
 }
 
-fn calc_gateway(ipam_result: &mut ExecResult, net_conf: &NetConf) -> CniResult<Vec<GatewayInfo>> {
-    let mut ips = ipam_result
-        .ips
-        .as_deref_mut()
-        .ok_or(anyhow!("IPAM plugin returned missing IP config"))?;
+fn calc_gateway(ipam_result: &mut SuccessReply, net_conf: &NetConf) -> CniResult<Vec<GatewayInfo>> {
+    if ipam_result.ips.is_empty() {
+        bail!("IPAM plugin returned missing IP config");
+    }
+    let ips = &mut ipam_result.ips;
 
     let mut gws = Vec::new();
     let is_default_gw = net_conf.is_default_gw.clone().unwrap_or(false);
@@ -184,20 +328,18 @@ fn calc_gateway(ipam_result: &mut ExecResult, net_conf: &NetConf) -> CniResult<V
         // gateway address if necessary.
 
         if is_default_gw {
-            if let Some(routes) = ipam_result.routes.as_deref() {
-                for route in routes {
-                    if route.gw.is_some() && route.dst.ip().is_unspecified() {
-                        gw_info.default_route_found = true;
-                        break;
-                    }
+            for route in &ipam_result.routes {
+                if route.gw.is_some() && route.dst.ip().is_unspecified() {
+                    gw_info.default_route_found = true;
+                    break;
                 }
             }
             if !gw_info.default_route_found {
-                let route = Route {
-                    dst: IpNetwork::V4(Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).unwrap()),
-                    gw: ip.gateway,
-                };
-                ipam_result.routes.get_or_insert_with(Vec::new).push(route);
+                let route = Route::new(
+                    IpNetwork::V4(Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).unwrap()),
+                    ip.gateway,
+                );
+                ipam_result.routes.push(route);
             }
         }
         if is_gw {
@@ -236,9 +378,8 @@ fn setup_veth(
     if_name: &str,
     mtu: u32,
     hairpin_mode: bool,
-    promisc_mode: bool,
     vlan_id: u16,
-    vlans: Vec<u32>,
+    vlans: Vec<u16>,
     preserve_default_vlan: bool,
     mac: &str,
 ) -> CniResult<(Interface, Interface)> {
@@ -283,6 +424,10 @@ fn setup_veth(
     let host_veth =
         netlink_ng::link_by_name(&host_interface.name)?.ok_or(anyhow!("veth not found"))?;
     netlink_ng::link_set_master(&host_veth, br)?;
+    // Set either way, so a promiscuous bridge never reflects frames back
+    // out of the port they came in on
+    netlink_ng::link_set_hairpin(&host_veth, hairpin_mode)?;
+    setup_port_vlans(&host_veth, vlan_id, &vlans, preserve_default_vlan)?;
     let host_mac = host_veth
         .attrs()
         .hardware_addr
@@ -294,9 +439,55 @@ fn setup_veth(
     Ok((host_interface, container_interface))
 }
 
+/// Make the bridge port an access port for `vlan_id`, or a trunk port for
+/// `vlans`.
+fn setup_port_vlans(
+    port: &Link,
+    vlan_id: u16,
+    vlans: &[u16],
+    preserve_default_vlan: bool,
+) -> CniResult<()> {
+    if vlan_id != 0 {
+        // pvid and untagged, so the container sends and gets plain frames
+        netlink_ng::bridge_vlan_add(port, vlan_id, true, true, false, true)?;
+    }
+    for vlan in vlans {
+        netlink_ng::bridge_vlan_add(port, *vlan, false, false, false, true)?;
+    }
+    // Every new port joins VLAN 1, which would connect it to all other ports
+    if !preserve_default_vlan && (vlan_id != 0 || !vlans.is_empty()) {
+        netlink_ng::bridge_vlan_del(port, DEFAULT_VLAN_ID, false, false, false, true)?;
+    }
+    Ok(())
+}
+
+// The bridge is created by the first ADD, so a missing one is fine, but any
+// other kind of link holding its name would make every ADD fail.
+fn cmd_status(args: &CmdArgs, net_conf: &NetConf, exec: &dyn Exec) -> CniResult<()> {
+    let br_name = net_conf.br_name.as_deref().unwrap_or(DEFAULT_BR_NAME);
+    if let Err(e) = bridge_by_name(br_name) {
+        return Err(CniError::new(
+            ErrorCode::PluginNotAvailable,
+            format!("bridge {} is not usable", br_name),
+            format!("{:#}", e),
+        )
+        .into());
+    }
+    // Without addresses to hand out no ADD can succeed either
+    if !net_conf.ipam.plugin.is_empty() {
+        invoke::delegate_status(
+            &net_conf.ipam.plugin,
+            &args.stdin_data,
+            exec,
+            net_conf.ipam_timeout(),
+        )?;
+    }
+    Ok(())
+}
+
 fn setup_bridge(net_conf: &NetConf) -> CniResult<(Link, Interface)> {
     let vlan_filtering = net_conf.vlan.is_some() || net_conf.vlan_trunk.is_some();
-    let br_name = net_conf.br_name.as_deref().unwrap_or("cni0");
+    let br_name = net_conf.br_name.as_deref().unwrap_or(DEFAULT_BR_NAME);
     let mtu = net_conf.mtu.clone().unwrap_or(0);
 
     let br = ensure_bridge(
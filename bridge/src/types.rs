@@ -1,5 +1,10 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
+use cni_core::error::{CniError, ErrorCode};
+use cni_core::prelude::CniResult;
+use cni_core::result::PrevResult;
 use cni_core::types::IPAMConfig;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -55,14 +60,22 @@ pub struct NetConf {
     pub preserve_default_vlan: Option<bool>,
     #[serde(
         rename = "macspoofchk",
+        alias = "mac_spoof_chk",
         default,
         skip_serializing_if = "Option::is_none"
     )]
     pub mac_spoof_chk: Option<bool>,
     #[serde(rename = "enabledad", default, skip_serializing_if = "Option::is_none")]
     pub enable_dad: Option<bool>,
+    /// Seconds a run of the IPAM plugin may take before it is killed.
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub macspoofchk: Option<bool>,
+    pub timeout: Option<u64>,
+    #[serde(
+        rename = "prevResult",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub prev_result: Option<PrevResult>,
     // #[serde(default, skip_serializing_if = "Option::is_none")]
     // pub mac: Option<bool>,
 }
@@ -77,4 +90,69 @@ pub struct VlanTrunk {
     id: Option<i32>,
 }
 
+const MAX_VLAN_ID: i32 = 4094;
+
+pub fn invalid_config(msg: impl Into<String>, details: impl Into<String>) -> anyhow::Error {
+    CniError::new(ErrorCode::InvalidNetworkConfig, msg, details).into()
+}
+
+impl NetConf {
+    /// The timeout for runs of the IPAM plugin, if the config sets one.
+    pub fn ipam_timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    /// The access VLAN of the port, 0 if it has none.
+    pub fn vlan_id(&self) -> CniResult<u16> {
+        let vlan = self.vlan.unwrap_or_default();
+        if !(0..=MAX_VLAN_ID).contains(&vlan) {
+            return Err(invalid_config(
+                format!("invalid VLAN ID {}", vlan),
+                "must be between 0 and 4094",
+            ));
+        }
+        Ok(vlan as u16)
+    }
+
+    /// The VLANs the port is a trunk for, sorted and without duplicates.
+    pub fn trunk_vlans(&self) -> CniResult<Vec<u16>> {
+        let mut vlans = vec![];
+        for trunk in self.vlan_trunk.iter().flatten() {
+            match (trunk.min_id, trunk.max_id) {
+                (None, None) => {}
+                (Some(min_id), Some(max_id))
+                    if is_vlan_id(min_id) && is_vlan_id(max_id) && min_id <= max_id =>
+                {
+                    vlans.extend(min_id as u16..=max_id as u16);
+                }
+                _ => {
+                    return Err(invalid_config(
+                        "incorrect trunk minID/maxID parameter",
+                        format!(
+                            "{:?} to {:?} is not a range within 1-4094",
+                            trunk.min_id, trunk.max_id
+                        ),
+                    ))
+                }
+            }
+            if let Some(id) = trunk.id {
+                if !is_vlan_id(id) {
+                    return Err(invalid_config(
+                        "incorrect trunk id parameter",
+                        format!("{} is not within 1-4094", id),
+                    ));
+                }
+                vlans.push(id as u16);
+            }
+        }
+        vlans.sort_unstable();
+        vlans.dedup();
+        Ok(vlans)
+    }
+}
+
+fn is_vlan_id(id: i32) -> bool {
+    (1..=MAX_VLAN_ID).contains(&id)
+}
+
 // pub struct RuntimeConfig {}
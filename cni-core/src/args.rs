@@ -0,0 +1,178 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::error::{CniError, ErrorCode};
+use crate::prelude::CniResult;
+use crate::types::MacAddr;
+
+/// A typed view of `CNI_ARGS`.
+///
+/// Each `K=V` pair is offered to [`LoadArgs::set_arg`] in turn, a key the
+/// type does not know is rejected unless `IgnoreUnknown=1` was passed too.
+pub trait LoadArgs: Default {
+    /// Store `value` for `key`, returning `false` when `key` is unknown.
+    fn set_arg(&mut self, key: &str, value: &str) -> CniResult<bool>;
+
+    fn ignore_unknown(&self) -> bool;
+}
+
+fn args_error(msg: String) -> anyhow::Error {
+    CniError::new(ErrorCode::InvalidEnvironmentVariables, msg, "").into()
+}
+
+/// Split `K=V;K=V` into its pairs, keeping their order.
+pub fn parse_args(args: &str) -> CniResult<Vec<(String, String)>> {
+    if args.is_empty() {
+        return Ok(vec![]);
+    }
+    args.split(';')
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) if !k.is_empty() && !v.contains('=') => Ok((k.to_string(), v.to_string())),
+            _ => Err(args_error(format!("ARGS: invalid pair {:?}", pair))),
+        })
+        .collect()
+}
+
+pub fn load_args<T: LoadArgs>(args: &str) -> CniResult<T> {
+    let mut result = T::default();
+    let mut unknown = vec![];
+    for (key, value) in parse_args(args)? {
+        if !result.set_arg(&key, &value)? {
+            unknown.push(format!("{}={}", key, value));
+        }
+    }
+    if !unknown.is_empty() && !result.ignore_unknown() {
+        return Err(args_error(format!("ARGS: unknown args {:?}", unknown)));
+    }
+    Ok(result)
+}
+
+/// Parse the value of one pair, for use in [`LoadArgs::set_arg`].
+pub fn parse_value<T>(key: &str, value: &str) -> CniResult<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse::<T>().map_err(|e| {
+        args_error(format!(
+            "ARGS: error parsing value of pair \"{}={}\": {}",
+            key, value, e
+        ))
+    })
+}
+
+/// `IgnoreUnknown` accepts the same spellings as libcni's `UnmarshallableBool`.
+pub fn parse_bool(key: &str, value: &str) -> CniResult<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(args_error(format!(
+            "ARGS: error parsing value of pair \"{}={}\": boolean unmarshal error",
+            key, value
+        ))),
+    }
+}
+
+/// The only key every plugin understands.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommonArgs {
+    pub ignore_unknown: bool,
+}
+
+impl LoadArgs for CommonArgs {
+    fn set_arg(&mut self, key: &str, value: &str) -> CniResult<bool> {
+        match key {
+            "IgnoreUnknown" => self.ignore_unknown = parse_bool(key, value)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn ignore_unknown(&self) -> bool {
+        self.ignore_unknown
+    }
+}
+
+/// The keys kubelet and friends pass along with every attachment.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct K8sArgs {
+    pub common: CommonArgs,
+    pub ip: Option<IpAddr>,
+    pub mac: Option<MacAddr>,
+    pub k8s_pod_name: Option<String>,
+    pub k8s_pod_namespace: Option<String>,
+    pub k8s_pod_infra_container_id: Option<String>,
+    pub k8s_pod_uid: Option<String>,
+}
+
+impl LoadArgs for K8sArgs {
+    fn set_arg(&mut self, key: &str, value: &str) -> CniResult<bool> {
+        match key {
+            "IP" => self.ip = Some(parse_value(key, value)?),
+            "MAC" => self.mac = Some(parse_value(key, value)?),
+            "K8S_POD_NAME" => self.k8s_pod_name = Some(value.to_string()),
+            "K8S_POD_NAMESPACE" => self.k8s_pod_namespace = Some(value.to_string()),
+            "K8S_POD_INFRA_CONTAINER_ID" => {
+                self.k8s_pod_infra_container_id = Some(value.to_string())
+            }
+            "K8S_POD_UID" => self.k8s_pod_uid = Some(value.to_string()),
+            _ => return self.common.set_arg(key, value),
+        }
+        Ok(true)
+    }
+
+    fn ignore_unknown(&self) -> bool {
+        self.common.ignore_unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        assert!(parse_args("").unwrap().is_empty());
+        assert_eq!(
+            parse_args("A=1;B=2").unwrap(),
+            vec![("A".into(), "1".into()), ("B".into(), "2".into())]
+        );
+        assert!(parse_args("A=1;").is_err());
+        assert!(parse_args("A").is_err());
+        assert!(parse_args("A=1=2").is_err());
+    }
+
+    #[test]
+    fn test_load_k8s_args() {
+        let args: K8sArgs = load_args(
+            "IgnoreUnknown=1;K8S_POD_NAMESPACE=default;K8S_POD_NAME=web-0;\
+             K8S_POD_INFRA_CONTAINER_ID=abc123;IP=10.0.0.5;MAC=00:11:22:33:44:55;FOO=bar",
+        )
+        .unwrap();
+        assert!(args.common.ignore_unknown);
+        assert_eq!(args.k8s_pod_name.as_deref(), Some("web-0"));
+        assert_eq!(args.k8s_pod_namespace.as_deref(), Some("default"));
+        assert_eq!(args.k8s_pod_infra_container_id.as_deref(), Some("abc123"));
+        assert_eq!(args.ip, Some("10.0.0.5".parse().unwrap()));
+        assert_eq!(args.mac, Some("00:11:22:33:44:55".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_unknown_args_rejected() {
+        let err = load_args::<K8sArgs>("K8S_POD_NAME=web-0;FOO=bar").unwrap_err();
+        let err = CniError::from(err);
+        assert_eq!(err.code, ErrorCode::InvalidEnvironmentVariables);
+        assert!(err.msg.contains("FOO=bar"));
+
+        // IgnoreUnknown applies no matter where it appears
+        assert!(load_args::<K8sArgs>("FOO=bar;IgnoreUnknown=true").is_ok());
+        assert!(load_args::<K8sArgs>("FOO=bar;IgnoreUnknown=0").is_err());
+    }
+
+    #[test]
+    fn test_bad_values() {
+        assert!(load_args::<K8sArgs>("IP=not-an-ip").is_err());
+        assert!(load_args::<K8sArgs>("MAC=zz:11").is_err());
+        assert!(load_args::<CommonArgs>("IgnoreUnknown=maybe").is_err());
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map, Value};
+
+use crate::error::{CniError, ErrorCode};
+use crate::prelude::CniResult;
+use crate::result::VersionedResult;
+use crate::types::NetConf;
+use crate::version;
+
+pub const CONF_EXTENSIONS: &[&str] = &["conf", "json"];
+pub const CONF_LIST_EXTENSIONS: &[&str] = &["conflist"];
+
+/// A single plugin configuration, as loaded from a `.conf` file or taken from
+/// the `plugins` array of a config list.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    pub network: NetConf,
+    pub bytes: Vec<u8>,
+}
+
+/// A network configuration list, as loaded from a `.conflist` file.
+#[derive(Clone, Debug)]
+pub struct NetworkConfigList {
+    pub name: String,
+    pub cni_version: String,
+    pub disable_check: bool,
+    pub disable_gc: bool,
+    pub plugins: Vec<NetworkConfig>,
+    pub bytes: Vec<u8>,
+}
+
+fn invalid_config(msg: impl Into<String>, details: impl Into<String>) -> anyhow::Error {
+    CniError::new(ErrorCode::InvalidNetworkConfig, msg, details).into()
+}
+
+fn decode_error(e: serde_json::Error) -> anyhow::Error {
+    CniError::new(
+        ErrorCode::DecodingFailure,
+        "error parsing configuration",
+        e.to_string(),
+    )
+    .into()
+}
+
+/// Whether `name` matches `^[a-zA-Z0-9][a-zA-Z0-9_.\-]*$`, the pattern libcni
+/// uses for both network names and container IDs.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let first_ok = chars.next().is_some_and(|c| c.is_ascii_alphanumeric());
+    first_ok && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Network names end up in file names and iptables chains, so keep them to
+/// the same characters libcni accepts.
+pub fn validate_network_name(name: &str) -> CniResult<()> {
+    if name.is_empty() {
+        return Err(invalid_config("missing network name", ""));
+    }
+    if !is_valid_name(name) {
+        return Err(invalid_config(
+            "invalid network name",
+            format!("{:?} must match ^[a-zA-Z0-9][a-zA-Z0-9_.\\-]*$", name),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_cni_version(cni_version: &str) -> CniResult<()> {
+    if !version::ALL.contains(&cni_version) {
+        return Err(CniError::new(
+            ErrorCode::IncompatibleCniVersion,
+            "unsupported cniVersion",
+            format!(
+                "{:?}, supported versions are {:?}",
+                cni_version,
+                version::ALL
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// libcni accepts both JSON booleans and their string spellings here.
+fn parse_bool_key(raw: &Map<String, Value>, key: &str) -> CniResult<bool> {
+    match raw.get(key) {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(Value::String(s)) => match s.to_ascii_lowercase().as_str() {
+            "1" | "t" | "true" => Ok(true),
+            "0" | "f" | "false" => Ok(false),
+            _ => Err(invalid_config(
+                format!("invalid value for {}", key),
+                format!("{:?} is not a boolean", s),
+            )),
+        },
+        Some(other) => Err(invalid_config(
+            format!("invalid value for {}", key),
+            format!("{} is not a boolean", other),
+        )),
+    }
+}
+
+pub fn conf_from_bytes(bytes: &[u8]) -> CniResult<NetworkConfig> {
+    let network: NetConf = serde_json::from_slice(bytes).map_err(decode_error)?;
+    if network.plugin.is_empty() {
+        return Err(invalid_config("missing 'type'", ""));
+    }
+    Ok(NetworkConfig {
+        network,
+        bytes: bytes.to_vec(),
+    })
+}
+
+pub fn conf_from_file(path: impl AsRef<Path>) -> CniResult<NetworkConfig> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)?;
+    conf_from_bytes(&bytes).map_err(|e| e.context(format!("error parsing {}", path.display())))
+}
+
+pub fn conf_list_from_bytes(bytes: &[u8]) -> CniResult<NetworkConfigList> {
+    let raw: Map<String, Value> = serde_json::from_slice(bytes).map_err(decode_error)?;
+
+    let name = match raw.get("name") {
+        Some(Value::String(name)) => name.clone(),
+        Some(_) => return Err(invalid_config("error parsing name", "not a string")),
+        None => return Err(invalid_config("no name", "")),
+    };
+    validate_network_name(&name)?;
+
+    let cni_version = match raw.get("cniVersion") {
+        Some(Value::String(v)) => v.clone(),
+        Some(_) => return Err(invalid_config("error parsing cniVersion", "not a string")),
+        None => String::new(),
+    };
+    if !cni_version.is_empty() {
+        validate_cni_version(&cni_version)?;
+    }
+
+    let disable_check = parse_bool_key(&raw, "disableCheck")?;
+    let disable_gc = parse_bool_key(&raw, "disableGC")?;
+
+    let plugins = match raw.get("plugins") {
+        Some(Value::Array(plugins)) => plugins,
+        Some(_) => return Err(invalid_config("error parsing plugins", "not an array")),
+        None => return Err(invalid_config("no plugins in list", "")),
+    };
+    if plugins.is_empty() {
+        return Err(invalid_config("no plugins in list", ""));
+    }
+
+    let plugins = plugins
+        .iter()
+        .enumerate()
+        .map(|(i, plugin)| {
+            let bytes = serde_json::to_vec(plugin)?;
+            conf_from_bytes(&bytes).map_err(|e| e.context(format!("failed to parse plugin {}", i)))
+        })
+        .collect::<CniResult<Vec<_>>>()?;
+
+    Ok(NetworkConfigList {
+        name,
+        cni_version,
+        disable_check,
+        disable_gc,
+        plugins,
+        bytes: bytes.to_vec(),
+    })
+}
+
+pub fn conf_list_from_file(path: impl AsRef<Path>) -> CniResult<NetworkConfigList> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)?;
+    conf_list_from_bytes(&bytes).map_err(|e| e.context(format!("error parsing {}", path.display())))
+}
+
+/// Wrap a single plugin config into a list of one.
+pub fn conf_list_from_conf(conf: &NetworkConfig) -> CniResult<NetworkConfigList> {
+    let mut raw: Map<String, Value> = serde_json::from_slice(&conf.bytes)?;
+    let mut list = Map::new();
+    list.insert("name".into(), Value::String(conf.network.name.clone()));
+    if let Some(cni_version) = raw.remove("cniVersion") {
+        list.insert("cniVersion".into(), cni_version);
+    }
+    raw.remove("name");
+    list.insert("plugins".into(), Value::Array(vec![Value::Object(raw)]));
+    conf_list_from_bytes(&serde_json::to_vec(&list)?)
+}
+
+/// List the files in `dir` with one of `extensions`, sorted by name.
+///
+/// A missing directory yields no files rather than an error.
+pub fn conf_files(dir: impl AsRef<Path>, extensions: &[&str]) -> CniResult<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir.as_ref()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut files = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        let matched = path
+            .extension()
+            .and_then(|it| it.to_str())
+            .is_some_and(|ext| extensions.contains(&ext));
+        if matched {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Find the single plugin config named `name` in `dir`.
+pub fn load_conf(dir: impl AsRef<Path>, name: &str) -> CniResult<NetworkConfig> {
+    let dir = dir.as_ref();
+    let files = conf_files(dir, CONF_EXTENSIONS)?;
+    if files.is_empty() {
+        return Err(invalid_config(
+            "no net configurations found",
+            dir.display().to_string(),
+        ));
+    }
+    for file in files {
+        let conf = conf_from_file(&file)?;
+        if conf.network.name == name {
+            return Ok(conf);
+        }
+    }
+    Err(invalid_config(
+        format!("no net configuration with name {:?}", name),
+        dir.display().to_string(),
+    ))
+}
+
+/// Find the config list named `name` in `dir`, falling back to a single
+/// plugin config wrapped into a list.
+pub fn load_conf_list(dir: impl AsRef<Path>, name: &str) -> CniResult<NetworkConfigList> {
+    let dir = dir.as_ref();
+    for file in conf_files(dir, CONF_LIST_EXTENSIONS)? {
+        let list = conf_list_from_file(&file)?;
+        if list.name == name {
+            return Ok(list);
+        }
+    }
+    let conf = load_conf(dir, name)?;
+    conf_list_from_conf(&conf)
+}
+
+impl NetworkConfig {
+    /// Return a copy of this config with `values` set at the top level.
+    pub fn inject(&self, values: Map<String, Value>) -> CniResult<NetworkConfig> {
+        let mut raw: Map<String, Value> = serde_json::from_slice(&self.bytes)?;
+        for (key, value) in values {
+            if key.is_empty() {
+                return Err(invalid_config("keys cannot be empty", ""));
+            }
+            raw.insert(key, value);
+        }
+        conf_from_bytes(&serde_json::to_vec(&raw)?)
+    }
+
+    /// Build the stdin for one invocation of this plugin.
+    ///
+    /// `name` and `cniVersion` come from the list, `prevResult` is converted to
+    /// that version, and `runtimeConfig` only receives the capability args the
+    /// plugin declared in its `capabilities`.
+    pub fn build_plugin_conf(
+        &self,
+        name: &str,
+        cni_version: &str,
+        prev_result: Option<&VersionedResult>,
+        capability_args: &HashMap<String, Value>,
+    ) -> CniResult<NetworkConfig> {
+        let mut values = Map::new();
+        values.insert("name".into(), Value::String(name.to_string()));
+        values.insert("cniVersion".into(), Value::String(cni_version.to_string()));
+        if let Some(prev_result) = prev_result {
+            let prev_result = prev_result.clone().convert_to(cni_version)?;
+            values.insert("prevResult".into(), serde_json::to_value(prev_result)?);
+        }
+
+        let runtime_config = self
+            .network
+            .capabilities
+            .iter()
+            .filter(|(_, enabled)| **enabled)
+            .filter_map(|(cap, _)| capability_args.get(cap).map(|v| (cap.clone(), v.clone())))
+            .collect::<Map<_, _>>();
+        if !runtime_config.is_empty() {
+            values.insert("runtimeConfig".into(), Value::Object(runtime_config));
+        }
+
+        self.inject(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const CONF_LIST: &str = r#"{
+        "cniVersion": "1.0.0",
+        "name": "dbnet",
+        "disableCheck": "true",
+        "plugins": [
+            {
+                "type": "bridge",
+                "bridge": "cni0",
+                "ipam": {"type": "host-local", "subnet": "10.1.0.0/16"}
+            },
+            {
+                "type": "portmap",
+                "capabilities": {"portMappings": true, "bandwidth": false}
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_conf_list_from_bytes() {
+        let list = conf_list_from_bytes(CONF_LIST.as_bytes()).unwrap();
+        assert_eq!(list.name, "dbnet");
+        assert_eq!(list.cni_version, "1.0.0");
+        assert!(list.disable_check);
+        assert!(!list.disable_gc);
+        assert_eq!(list.plugins.len(), 2);
+        assert_eq!(list.plugins[0].network.plugin, "bridge");
+        assert!(list.plugins[1].network.capabilities["portMappings"]);
+    }
+
+    #[test]
+    fn test_conf_list_validation() {
+        let err = conf_list_from_bytes(br#"{"name": "-bad", "plugins": [{"type": "a"}]}"#);
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("invalid network name"));
+
+        let err = conf_list_from_bytes(br#"{"name": "net", "plugins": []}"#);
+        assert!(err.unwrap_err().to_string().contains("no plugins"));
+
+        let err = conf_list_from_bytes(br#"{"name": "net", "plugins": [{"name": "x"}]}"#);
+        assert!(format!("{:#}", err.unwrap_err()).contains("missing 'type'"));
+
+        let data = br#"{"name": "net", "cniVersion": "0.5.0", "plugins": [{"type": "a"}]}"#;
+        let err = CniError::from(conf_list_from_bytes(data).unwrap_err());
+        assert_eq!(err.code, ErrorCode::IncompatibleCniVersion);
+
+        let data = br#"{"name": "net", "disableGC": "maybe", "plugins": [{"type": "a"}]}"#;
+        let err = CniError::from(conf_list_from_bytes(data).unwrap_err());
+        assert_eq!(err.code, ErrorCode::InvalidNetworkConfig);
+    }
+
+    #[test]
+    fn test_conf_list_from_conf() {
+        let conf = conf_from_bytes(br#"{"cniVersion": "0.4.0", "name": "one", "type": "bridge"}"#)
+            .unwrap();
+        let list = conf_list_from_conf(&conf).unwrap();
+        assert_eq!(list.name, "one");
+        assert_eq!(list.cni_version, "0.4.0");
+        assert_eq!(list.plugins[0].network.plugin, "bridge");
+    }
+
+    #[test]
+    fn test_build_plugin_conf() {
+        let list = conf_list_from_bytes(CONF_LIST.as_bytes()).unwrap();
+        let prev_result = VersionedResult::from_value(json!({
+            "cniVersion": "1.0.0",
+            "ips": [{"address": "10.1.0.5/16", "interface": 0}],
+            "interfaces": [{"name": "eth0"}]
+        }))
+        .unwrap();
+        let capability_args = HashMap::from([
+            ("portMappings".to_string(), json!([{"hostPort": 8080}])),
+            ("bandwidth".to_string(), json!({"ingressRate": 1})),
+        ]);
+
+        let conf = list.plugins[1]
+            .build_plugin_conf("dbnet", "0.4.0", Some(&prev_result), &capability_args)
+            .unwrap();
+        let stdin: Value = serde_json::from_slice(&conf.bytes).unwrap();
+        assert_eq!(stdin["name"], "dbnet");
+        assert_eq!(stdin["cniVersion"], "0.4.0");
+        assert_eq!(stdin["prevResult"]["cniVersion"], "0.4.0");
+        assert_eq!(stdin["prevResult"]["ips"][0]["version"], "4");
+        assert_eq!(stdin["runtimeConfig"]["portMappings"][0]["hostPort"], 8080);
+        assert_eq!(stdin["runtimeConfig"].get("bandwidth"), None);
+
+        let conf = list.plugins[0]
+            .build_plugin_conf("dbnet", "1.0.0", None, &capability_args)
+            .unwrap();
+        let stdin: Value = serde_json::from_slice(&conf.bytes).unwrap();
+        assert_eq!(stdin.get("prevResult"), None);
+        assert_eq!(stdin.get("runtimeConfig"), None);
+        assert_eq!(stdin["ipam"]["subnet"], "10.1.0.0/16");
+    }
+
+    #[test]
+    fn test_load_conf_list_from_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("nested.conf")).unwrap();
+        std::fs::write(dir.join("20-db.conflist"), CONF_LIST).unwrap();
+        std::fs::write(
+            dir.join("10-single.conf"),
+            r#"{"cniVersion": "1.0.0", "name": "single", "type": "bridge"}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("05-other.json"),
+            r#"{"name": "other", "type": "ptp"}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("README"), "not a config").unwrap();
+
+        let files = conf_files(dir, CONF_EXTENSIONS).unwrap();
+        assert_eq!(
+            files,
+            vec![dir.join("05-other.json"), dir.join("10-single.conf")]
+        );
+
+        assert_eq!(load_conf_list(dir, "dbnet").unwrap().plugins.len(), 2);
+        let list = load_conf_list(dir, "single").unwrap();
+        assert_eq!(list.plugins[0].network.plugin, "bridge");
+        assert!(load_conf_list(dir, "missing").is_err());
+        assert!(conf_files(dir.join("absent"), CONF_EXTENSIONS)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,3 +1,7 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
 pub fn anyhow_io_kind(e: &anyhow::Error) -> Option<std::io::ErrorKind> {
     e.downcast_ref::<std::io::Error>().map(|it| it.kind())
 }
@@ -15,3 +19,179 @@ macro_rules! wrap_err {
         $e.map_err(|e| anyhow::anyhow!("{:?}", e))
     };
 }
+
+/// Well-known error codes from the CNI spec.
+///
+/// Codes 1-99 are reserved by the spec, plugins may use 100 and up for
+/// their own errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    IncompatibleCniVersion,
+    UnsupportedField,
+    UnknownContainer,
+    InvalidEnvironmentVariables,
+    IoFailure,
+    DecodingFailure,
+    InvalidNetworkConfig,
+    TryAgainLater,
+    PluginNotAvailable,
+    LimitedConnectivity,
+    Internal,
+    /// A code below 100 the spec does not define (yet).
+    Reserved(u32),
+    /// A plugin-specific code, 100 or above.
+    Plugin(u32),
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> u32 {
+        match self {
+            ErrorCode::IncompatibleCniVersion => 1,
+            ErrorCode::UnsupportedField => 2,
+            ErrorCode::UnknownContainer => 3,
+            ErrorCode::InvalidEnvironmentVariables => 4,
+            ErrorCode::IoFailure => 5,
+            ErrorCode::DecodingFailure => 6,
+            ErrorCode::InvalidNetworkConfig => 7,
+            ErrorCode::TryAgainLater => 11,
+            ErrorCode::PluginNotAvailable => 50,
+            ErrorCode::LimitedConnectivity => 51,
+            ErrorCode::Internal => 999,
+            ErrorCode::Reserved(code) | ErrorCode::Plugin(code) => *code,
+        }
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            1 => ErrorCode::IncompatibleCniVersion,
+            2 => ErrorCode::UnsupportedField,
+            3 => ErrorCode::UnknownContainer,
+            4 => ErrorCode::InvalidEnvironmentVariables,
+            5 => ErrorCode::IoFailure,
+            6 => ErrorCode::DecodingFailure,
+            7 => ErrorCode::InvalidNetworkConfig,
+            11 => ErrorCode::TryAgainLater,
+            50 => ErrorCode::PluginNotAvailable,
+            51 => ErrorCode::LimitedConnectivity,
+            999 => ErrorCode::Internal,
+            code if code < 100 => ErrorCode::Reserved(code),
+            code => ErrorCode::Plugin(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        code.code()
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+/// A CNI error result, printed to stdout when a command fails.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, thiserror::Error)]
+pub struct CniError {
+    pub code: ErrorCode,
+    pub msg: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub details: String,
+}
+
+impl CniError {
+    pub fn new(code: ErrorCode, msg: impl Into<String>, details: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+            details: details.into(),
+        }
+    }
+}
+
+impl fmt::Display for CniError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.details.is_empty() {
+            write!(f, "{}", self.msg)
+        } else {
+            write!(f, "{}; {}", self.msg, self.details)
+        }
+    }
+}
+
+impl From<anyhow::Error> for CniError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<CniError>() {
+            Ok(cni_err) => return cni_err,
+            Err(e) => e,
+        };
+        let code = if e.is::<std::io::Error>() {
+            ErrorCode::IoFailure
+        } else if e.is::<serde_json::Error>() {
+            ErrorCode::DecodingFailure
+        } else {
+            ErrorCode::Internal
+        };
+        CniError::new(code, format!("{:#}", e), "")
+    }
+}
+
+/// The error document written to stdout, tagged with the config's version.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorReply {
+    pub cni_version: String,
+    #[serde(flatten)]
+    pub error: CniError,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code_round_trip() {
+        for code in [1, 2, 3, 4, 5, 6, 7, 11, 100, 999] {
+            assert_eq!(ErrorCode::from(code).code(), code);
+        }
+        assert_eq!(ErrorCode::from(101), ErrorCode::Plugin(101));
+        assert_eq!(ErrorCode::from(100), ErrorCode::Plugin(100));
+        for code in [0, 8, 12, 99] {
+            assert_eq!(ErrorCode::from(code), ErrorCode::Reserved(code));
+            assert_eq!(ErrorCode::from(code).code(), code);
+        }
+        let err: CniError = serde_json::from_str(r#"{"code": 42, "msg": "future"}"#).unwrap();
+        assert_eq!(err.code, ErrorCode::Reserved(42));
+    }
+
+    #[test]
+    fn test_error_reply_encode() {
+        let reply = ErrorReply {
+            cni_version: "1.0.0".to_string(),
+            error: CniError::new(ErrorCode::InvalidNetworkConfig, "bad config", ""),
+        };
+        assert_eq!(
+            serde_json::to_string(&reply).unwrap(),
+            r#"{"cniVersion":"1.0.0","code":7,"msg":"bad config"}"#
+        );
+    }
+
+    #[test]
+    fn test_from_anyhow() {
+        let err = anyhow::Error::new(CniError::new(ErrorCode::TryAgainLater, "busy", "x"));
+        assert_eq!(CniError::from(err).code, ErrorCode::TryAgainLater);
+
+        let err = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(CniError::from(err).code, ErrorCode::IoFailure);
+
+        let err = anyhow::anyhow!("something broke");
+        let cni_err = CniError::from(err);
+        assert_eq!(cni_err.code, ErrorCode::Internal);
+        assert_eq!(cni_err.msg, "something broke");
+    }
+}
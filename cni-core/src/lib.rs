@@ -1,7 +1,11 @@
+pub mod args;
+pub mod config;
 pub mod error;
 pub mod logger;
 pub mod prelude;
+pub mod result;
 pub mod skel;
 pub mod types;
+pub mod version;
 
 pub use error::*;
@@ -1,46 +1,426 @@
-use std::fs::OpenOptions;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
+use std::sync::Mutex;
 
-use log::LevelFilter;
-use simplelog::{
-    ColorChoice, CombinedLogger, ConfigBuilder, TermLogger, TerminalMode, WriteLogger,
-};
+use anyhow::{anyhow, bail};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use simplelog::{CombinedLogger, Config, ConfigBuilder, SharedLogger, WriteLogger};
 
 use crate::prelude::*;
 
 // pub const LOG_DIR: &'static str = "/var/log/cni/";
 pub const LOG_DIR: &str = "/tmp/log/cni/";
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Rotated files are kept as `<file>.1` (newest) up to `<file>.<MAX_BACKUPS>`.
+pub const MAX_BACKUPS: usize = 3;
 
-pub fn init(log_name: impl AsRef<Path>) -> CniResult<()> {
-    let config = {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogOutput {
+    File(PathBuf),
+    Stderr,
+    Syslog(PathBuf),
+}
+
+/// Where and how much a plugin logs.
+///
+/// Each setting is read from the environment first and the network config
+/// second, so a node can be switched to debug logging without touching any
+/// config:
+///
+/// | env              | net config   | default              |
+/// |------------------|--------------|----------------------|
+/// | `CNI_LOG_LEVEL`  | `logLevel`   | `info`               |
+/// | `CNI_LOG_DIR`    | `logDir`     | [`LOG_DIR`]          |
+/// | `CNI_LOG_FILE`   | `logFile`    | `<plugin name>.log`  |
+/// | `CNI_LOG_OUTPUT` | `logOutput`  | `file`               |
+/// | `CNI_LOG_MAX_SIZE` | `logMaxSize` | [`DEFAULT_MAX_SIZE`] |
+///
+/// The output is one of `file`, `stderr`, `syslog` (sent to [`SYSLOG_SOCKET`])
+/// or `syslog:<socket path>`. A relative log file is taken relative to the
+/// log dir, and a max size of 0 turns rotation off.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    pub level: LevelFilter,
+    pub output: LogOutput,
+    pub max_size: u64,
+}
+
+impl LogConfig {
+    pub fn load(name: &str, net_conf: &[u8]) -> CniResult<LogConfig> {
+        Self::from_sources(name, net_conf, |key| std::env::var(key).ok())
+    }
+
+    fn from_sources(
+        name: &str,
+        net_conf: &[u8],
+        var: impl Fn(&str) -> Option<String>,
+    ) -> CniResult<LogConfig> {
+        // A config that does not parse is reported by the skel, not here
+        let net_conf: Map<String, Value> = serde_json::from_slice(net_conf).unwrap_or_default();
+        let setting = |env: &str, key: &str| -> Option<String> {
+            var(env)
+                .filter(|it| !it.is_empty())
+                .or_else(|| match net_conf.get(key) {
+                    Some(Value::String(s)) => Some(s.clone()),
+                    Some(Value::Number(n)) => Some(n.to_string()),
+                    _ => None,
+                })
+        };
+
+        let level = match setting("CNI_LOG_LEVEL", "logLevel") {
+            Some(level) => level
+                .parse::<LevelFilter>()
+                .map_err(|_| anyhow!("invalid log level {:?}", level))?,
+            None => LevelFilter::Info,
+        };
+        let max_size = match setting("CNI_LOG_MAX_SIZE", "logMaxSize") {
+            Some(size) => size
+                .parse::<u64>()
+                .map_err(|_| anyhow!("invalid log max size {:?}", size))?,
+            None => DEFAULT_MAX_SIZE,
+        };
+        let dir = PathBuf::from(setting("CNI_LOG_DIR", "logDir").unwrap_or(LOG_DIR.into()));
+        let file = setting("CNI_LOG_FILE", "logFile").unwrap_or(format!("{}.log", name));
+
+        let output = setting("CNI_LOG_OUTPUT", "logOutput").unwrap_or("file".into());
+        let output = match output.as_str() {
+            "file" => LogOutput::File(dir.join(file)),
+            "stderr" => LogOutput::Stderr,
+            "syslog" => LogOutput::Syslog(SYSLOG_SOCKET.into()),
+            _ => match output.strip_prefix("syslog:") {
+                Some(socket) => LogOutput::Syslog(socket.into()),
+                None => bail!("invalid log output {:?}", output),
+            },
+        };
+
+        Ok(LogConfig {
+            level,
+            output,
+            max_size,
+        })
+    }
+}
+
+/// Install the global logger.
+///
+/// `name` identifies the plugin to syslog, and `tag` is put on every line so
+/// one invocation can be followed across the plugins it delegates to.
+pub fn init(config: &LogConfig, name: &str, tag: &str) -> CniResult<()> {
+    let format = {
         let mut builder = ConfigBuilder::new();
-        builder.set_thread_level(LevelFilter::Info);
+        builder.set_thread_level(LevelFilter::Off);
         builder.set_target_level(LevelFilter::Info);
         builder.build()
     };
-    let term_logger = TermLogger::new(
-        LevelFilter::Info,
-        config.clone(),
-        TerminalMode::Stderr,
-        ColorChoice::Never,
-    );
-    let log_dir = PathBuf::from_str(LOG_DIR)?;
-    let log_file = log_dir.join(log_name);
-    if !log_dir.exists() {
-        std::fs::create_dir_all(log_dir)?;
-    }
-    let file_logger = WriteLogger::new(
-        LevelFilter::Debug,
-        config.clone(),
-        OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(log_file)?,
-    );
-    CombinedLogger::init(vec![
-        // term_logger,
-        file_logger,
-    ])?;
+    let logger: Box<dyn SharedLogger> = match &config.output {
+        LogOutput::File(path) => WriteLogger::new(
+            config.level,
+            format,
+            TaggedWriter::new(tag, RotatingFile::open(path, config.max_size)?),
+        ),
+        LogOutput::Stderr => WriteLogger::new(
+            config.level,
+            format,
+            TaggedWriter::new(tag, std::io::stderr()),
+        ),
+        LogOutput::Syslog(socket) => {
+            Box::new(SyslogLogger::connect(socket, config.level, name, tag)?)
+        }
+    };
+    CombinedLogger::init(vec![logger])?;
     Ok(())
 }
+
+/// Prefixes every line with `[tag] `.
+///
+/// Lines are buffered until complete so that a line is never split by a
+/// rotation and never interleaved with another process writing the same file.
+/// A last line without a newline is written, terminated, on flush or drop.
+pub struct TaggedWriter<W: Write> {
+    prefix: Vec<u8>,
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> TaggedWriter<W> {
+    pub fn new(tag: &str, inner: W) -> Self {
+        Self {
+            prefix: format!("[{}] ", tag).into_bytes(),
+            inner,
+            buf: vec![],
+        }
+    }
+
+    fn write_partial_line(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let mut line = self.prefix.clone();
+        line.append(&mut self.buf);
+        line.push(b'\n');
+        self.inner.write_all(&line)
+    }
+}
+
+impl<W: Write> Write for TaggedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let mut line = self.prefix.clone();
+            line.extend(self.buf.drain(..=end));
+            self.inner.write_all(&line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_partial_line()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for TaggedWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// An append-only log file that is rotated once it grows past `max_size`.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl AsRef<Path>, max_size: u64) -> CniResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = Self::open_file(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            file,
+            size,
+        })
+    }
+
+    fn open_file(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new().append(true).create(true).open(path)
+    }
+
+    fn backup(&self, index: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..MAX_BACKUPS).rev() {
+            match std::fs::rename(self.backup(index), self.backup(index + 1)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        std::fs::rename(&self.path, self.backup(1))?;
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Sends one RFC 3164 datagram per record to a local syslog socket, which on
+/// systemd hosts is read by journald.
+pub struct SyslogLogger {
+    level: LevelFilter,
+    ident: String,
+    tag: String,
+    socket: Mutex<UnixDatagram>,
+}
+
+impl SyslogLogger {
+    const FACILITY_USER: u8 = 1;
+
+    pub fn connect(
+        socket: impl AsRef<Path>,
+        level: LevelFilter,
+        ident: &str,
+        tag: &str,
+    ) -> CniResult<Self> {
+        let socket = socket.as_ref();
+        let datagram = UnixDatagram::unbound()?;
+        datagram
+            .connect(socket)
+            .map_err(|e| anyhow!("failed to connect to {}: {}", socket.display(), e))?;
+        Ok(Self {
+            level,
+            ident: ident.to_string(),
+            tag: tag.to_string(),
+            socket: Mutex::new(datagram),
+        })
+    }
+
+    fn severity(level: Level) -> u8 {
+        match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = format!(
+            "<{}>{}[{}]: [{}] {}: {}",
+            Self::FACILITY_USER * 8 + Self::severity(record.level()),
+            self.ident,
+            std::process::id(),
+            self.tag,
+            record.target(),
+            record.args()
+        );
+        let _ = self.socket.lock().unwrap().send(message.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for SyslogLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn load(net_conf: &str, env: &[(&str, &str)]) -> CniResult<LogConfig> {
+        let env: HashMap<&str, &str> = env.iter().copied().collect();
+        LogConfig::from_sources("bridge", net_conf.as_bytes(), |key| {
+            env.get(key).map(|it| it.to_string())
+        })
+    }
+
+    #[test]
+    fn test_log_config_sources() {
+        let config = load("", &[]).unwrap();
+        assert_eq!(config.level, LevelFilter::Info);
+        assert_eq!(
+            config.output,
+            LogOutput::File(Path::new(LOG_DIR).join("bridge.log"))
+        );
+        assert_eq!(config.max_size, DEFAULT_MAX_SIZE);
+
+        let net_conf =
+            r#"{"name": "net", "logLevel": "debug", "logDir": "/var/log/cni", "logMaxSize": 1024}"#;
+        let config = load(net_conf, &[("CNI_LOG_LEVEL", "warn")]).unwrap();
+        assert_eq!(config.level, LevelFilter::Warn);
+        assert_eq!(
+            config.output,
+            LogOutput::File("/var/log/cni/bridge.log".into())
+        );
+        assert_eq!(config.max_size, 1024);
+
+        let config = load(net_conf, &[("CNI_LOG_FILE", "/tmp/other.log")]).unwrap();
+        assert_eq!(config.output, LogOutput::File("/tmp/other.log".into()));
+
+        let config = load(r#"{"logOutput": "syslog:/run/log"}"#, &[]).unwrap();
+        assert_eq!(config.output, LogOutput::Syslog("/run/log".into()));
+        let config = load("", &[("CNI_LOG_OUTPUT", "stderr")]).unwrap();
+        assert_eq!(config.output, LogOutput::Stderr);
+
+        assert!(load(r#"{"logLevel": "loud"}"#, &[]).is_err());
+        assert!(load(r#"{"logOutput": "pipe"}"#, &[]).is_err());
+    }
+
+    #[test]
+    fn test_tagged_writer() {
+        let mut writer = TaggedWriter::new("ADD abc", Vec::new());
+        write!(writer, "first ").unwrap();
+        write!(writer, "line\nsecond line\nthird").unwrap();
+        assert_eq!(
+            String::from_utf8(writer.inner.clone()).unwrap(),
+            "[ADD abc] first line\n[ADD abc] second line\n"
+        );
+        writer.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(writer.inner.clone()).unwrap(),
+            "[ADD abc] first line\n[ADD abc] second line\n[ADD abc] third\n"
+        );
+
+        let mut out = Vec::new();
+        let mut writer = TaggedWriter::new("DEL abc", &mut out);
+        write!(writer, "done\nleft over").unwrap();
+        drop(writer);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[DEL abc] done\n[DEL abc] left over\n"
+        );
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log").join("plugin.log");
+
+        let mut file = RotatingFile::open(&path, 10).unwrap();
+        for line in [
+            "aaaaaaaa\n",
+            "bbbbbbbb\n",
+            "cccccccc\n",
+            "dddddddd\n",
+            "eeeeeeee\n",
+        ] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "eeeeeeee\n");
+        assert_eq!(read(file.backup(1)), "dddddddd\n");
+        assert_eq!(read(file.backup(3)), "bbbbbbbb\n");
+        assert!(!file.backup(4).exists());
+    }
+}
@@ -0,0 +1,446 @@
+use serde::de::Error as _;
+use std::ops::Deref;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::error::{CniError, ErrorCode};
+use crate::prelude::CniResult;
+use crate::types::{Interface, Ip, Route, SuccessReply};
+use crate::version;
+
+pub mod v020;
+pub mod v040;
+
+/// A success result in the layout of one particular spec version.
+///
+/// Plugins build a [`SuccessReply`] (the 1.x layout) and convert it to the
+/// `cniVersion` they were called with, delegating plugins decode whatever their
+/// delegate printed and bring it up to the current layout.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum VersionedResult {
+    /// 0.1.0 and 0.2.0
+    V020(v020::Result),
+    /// 0.3.0, 0.3.1 and 0.4.0
+    V040(v040::Result),
+    /// 1.0.0 and 1.1.0
+    V100(SuccessReply),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Layout {
+    V020,
+    V040,
+    V100,
+}
+
+fn layout(version: &str) -> CniResult<Layout> {
+    match version {
+        "0.1.0" | "0.2.0" => Ok(Layout::V020),
+        "0.3.0" | "0.3.1" | "0.4.0" => Ok(Layout::V040),
+        "1.0.0" | "1.1.0" => Ok(Layout::V100),
+        _ => Err(CniError::new(
+            ErrorCode::IncompatibleCniVersion,
+            "unsupported CNI result version",
+            version,
+        )
+        .into()),
+    }
+}
+
+impl VersionedResult {
+    /// Decode a result using the `cniVersion` it carries.
+    ///
+    /// Results missing the key are decoded by their shape.
+    pub fn from_slice(data: &[u8]) -> CniResult<Self> {
+        Self::from_value(serde_json::from_slice(data)?)
+    }
+
+    pub fn from_value(value: Value) -> CniResult<Self> {
+        let version = value
+            .get("cniVersion")
+            .and_then(|it| it.as_str())
+            .filter(|it| !it.is_empty())
+            .unwrap_or_else(|| guess_version(&value))
+            .to_string();
+        let mut result = match layout(&version)? {
+            Layout::V020 => VersionedResult::V020(serde_json::from_value(value)?),
+            Layout::V040 => VersionedResult::V040(serde_json::from_value(value)?),
+            Layout::V100 => VersionedResult::V100(serde_json::from_value(value)?),
+        };
+        let cni_version = result.version_mut();
+        if cni_version.is_empty() {
+            *cni_version = version;
+        }
+        Ok(result)
+    }
+
+    fn version_mut(&mut self) -> &mut String {
+        match self {
+            VersionedResult::V020(r) => &mut r.cni_version,
+            VersionedResult::V040(r) => &mut r.cni_version,
+            VersionedResult::V100(r) => &mut r.cni_version,
+        }
+    }
+
+    pub fn version(&self) -> &str {
+        match self {
+            VersionedResult::V020(r) => &r.cni_version,
+            VersionedResult::V040(r) => &r.cni_version,
+            VersionedResult::V100(r) => &r.cni_version,
+        }
+    }
+
+    /// Bring the result up to the 1.x layout.
+    pub fn into_current(self) -> CniResult<SuccessReply> {
+        let result = match self {
+            VersionedResult::V100(r) => r,
+            VersionedResult::V040(r) => SuccessReply {
+                cni_version: version::CURRENT.to_string(),
+                interfaces: r.interfaces,
+                ips: r
+                    .ips
+                    .into_iter()
+                    .map(|ip| Ip {
+                        address: ip.address,
+                        gateway: ip.gateway,
+                        interface: ip.interface,
+                    })
+                    .collect(),
+                routes: r.routes,
+                dns: r.dns,
+                specific: Default::default(),
+            },
+            VersionedResult::V020(r) => {
+                let mut result = SuccessReply {
+                    cni_version: version::CURRENT.to_string(),
+                    dns: r.dns,
+                    ..Default::default()
+                };
+                for ip_config in [r.ip4, r.ip6].into_iter().flatten() {
+                    result.ips.push(Ip {
+                        address: ip_config.ip,
+                        gateway: ip_config.gateway,
+                        interface: None,
+                    });
+                    result.routes.extend(ip_config.routes);
+                }
+                result
+            }
+        };
+        Ok(result)
+    }
+
+    /// Convert the result to the layout of `version`.
+    pub fn convert_to(self, version: &str) -> CniResult<Self> {
+        let target = layout(version)?;
+        let mut current = self.into_current()?;
+        if version != "1.1.0" {
+            strip_v110_fields(&mut current);
+        }
+
+        let result = match target {
+            Layout::V100 => {
+                current.cni_version = version.to_string();
+                VersionedResult::V100(current)
+            }
+            Layout::V040 => VersionedResult::V040(v040::Result {
+                cni_version: version.to_string(),
+                interfaces: current.interfaces,
+                ips: current
+                    .ips
+                    .into_iter()
+                    .map(|ip| v040::IpConfig {
+                        version: if ip.address.is_ipv4() { "4" } else { "6" }.to_string(),
+                        interface: ip.interface,
+                        address: ip.address,
+                        gateway: ip.gateway,
+                    })
+                    .collect(),
+                routes: current.routes,
+                dns: current.dns,
+            }),
+            Layout::V020 => VersionedResult::V020(to_v020(current, version)?),
+        };
+        Ok(result)
+    }
+}
+
+// Results without `cniVersion` are judged by the fields only one layout has.
+fn guess_version(value: &Value) -> &'static str {
+    if value.get("ip4").is_some() || value.get("ip6").is_some() {
+        return version::DEFAULT_CONFIG_VERSION;
+    }
+    let has_ip_version = value
+        .get("ips")
+        .and_then(|it| it.as_array())
+        .is_some_and(|ips| ips.iter().any(|ip| ip.get("version").is_some()));
+    if has_ip_version {
+        "0.4.0"
+    } else {
+        "1.0.0"
+    }
+}
+
+// 0.2.0 and earlier only hold the first address of each family.
+fn to_v020(current: SuccessReply, version: &str) -> CniResult<v020::Result> {
+    let mut result = v020::Result {
+        cni_version: version.to_string(),
+        dns: current.dns,
+        ..Default::default()
+    };
+    for ip in current.ips {
+        let slot = if ip.address.is_ipv4() {
+            &mut result.ip4
+        } else {
+            &mut result.ip6
+        };
+        if slot.is_none() {
+            *slot = Some(v020::IpConfig {
+                ip: ip.address,
+                gateway: ip.gateway,
+                routes: vec![],
+            });
+        }
+    }
+    for route in current.routes {
+        let slot = if route.dst.is_ipv4() {
+            &mut result.ip4
+        } else {
+            &mut result.ip6
+        };
+        if let Some(ip_config) = slot {
+            ip_config.routes.push(route);
+        }
+    }
+    if result.ip4.is_none() && result.ip6.is_none() {
+        return Err(CniError::new(
+            ErrorCode::IncompatibleCniVersion,
+            "cannot convert result",
+            format!("no IP addresses to represent in version {}", version),
+        )
+        .into());
+    }
+    Ok(result)
+}
+
+fn strip_v110_fields(result: &mut SuccessReply) {
+    result
+        .interfaces
+        .iter_mut()
+        .for_each(Interface::strip_v110_fields);
+    result.routes.iter_mut().for_each(Route::strip_v110_fields);
+}
+
+/// The `prevResult` handed to a chained plugin.
+///
+/// It is decoded using the version it was printed in and brought up to the
+/// 1.x layout, the skel converts the plugin's output back to the config's
+/// version on the way out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PrevResult(SuccessReply);
+
+impl PrevResult {
+    pub fn into_inner(self) -> SuccessReply {
+        self.0
+    }
+}
+
+impl Deref for PrevResult {
+    type Target = SuccessReply;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<SuccessReply> for PrevResult {
+    fn from(r: SuccessReply) -> Self {
+        Self(r)
+    }
+}
+
+impl<'de> Deserialize<'de> for PrevResult {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let result = VersionedResult::deserialize(deserializer)?;
+        result.into_current().map(Self).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for PrevResult {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+impl From<SuccessReply> for VersionedResult {
+    fn from(r: SuccessReply) -> Self {
+        VersionedResult::V100(r)
+    }
+}
+
+impl From<v040::Result> for VersionedResult {
+    fn from(r: v040::Result) -> Self {
+        VersionedResult::V040(r)
+    }
+}
+
+impl From<v020::Result> for VersionedResult {
+    fn from(r: v020::Result) -> Self {
+        VersionedResult::V020(r)
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionedResult {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = Value::deserialize(deserializer)?;
+        Self::from_value(value).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::types::NetConf;
+
+    use super::*;
+
+    const RESULT_040: &str = r#"{
+        "cniVersion": "0.4.0",
+        "interfaces": [
+            {"name": "cni0", "mac": "00:11:22:33:44:55"},
+            {"name": "eth0", "mac": "00:11:22:33:44:66", "sandbox": "/var/run/netns/test"}
+        ],
+        "ips": [
+            {"version": "4", "interface": 1, "address": "10.1.2.3/24", "gateway": "10.1.2.1"},
+            {"version": "6", "interface": 1, "address": "abcd::1234/64"}
+        ],
+        "routes": [
+            {"dst": "0.0.0.0/0", "gw": "10.1.2.1"},
+            {"dst": "::/0"}
+        ],
+        "dns": {"nameservers": ["8.8.8.8"]}
+    }"#;
+
+    #[test]
+    fn test_decode_by_version() {
+        let result = VersionedResult::from_slice(RESULT_040.as_bytes()).unwrap();
+        assert!(matches!(result, VersionedResult::V040(_)));
+        assert_eq!(result.version(), "0.4.0");
+
+        let result = VersionedResult::from_slice(br#"{"ip4": {"ip": "10.1.2.3/24"}}"#).unwrap();
+        assert!(matches!(result, VersionedResult::V020(_)));
+
+        let result = VersionedResult::from_slice(br#"{"cniVersion": "9.9.9"}"#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_v040_round_trip() {
+        let original = VersionedResult::from_slice(RESULT_040.as_bytes()).unwrap();
+        let current = original.clone().convert_to("1.0.0").unwrap();
+        let value = serde_json::to_value(&current).unwrap();
+        assert_eq!(value["ips"][0].get("version"), None);
+        assert_eq!(value["ips"][0]["interface"], 1);
+
+        let back = current.convert_to("0.4.0").unwrap();
+        assert_eq!(back, original);
+        let back = back.convert_to("0.3.1").unwrap();
+        assert_eq!(back.version(), "0.3.1");
+    }
+
+    #[test]
+    fn test_v020_flatten() {
+        let result = VersionedResult::from_slice(RESULT_040.as_bytes())
+            .unwrap()
+            .convert_to("0.2.0")
+            .unwrap();
+        let VersionedResult::V020(r) = &result else {
+            panic!("expected a 0.2.0 result")
+        };
+        let ip4 = r.ip4.as_ref().unwrap();
+        assert_eq!(ip4.ip, "10.1.2.3/24".parse().unwrap());
+        assert_eq!(ip4.routes.len(), 1);
+        assert_eq!(r.ip6.as_ref().unwrap().routes.len(), 1);
+
+        let current = result.clone().into_current().unwrap();
+        assert_eq!(current.ips.len(), 2);
+        assert_eq!(current.routes.len(), 2);
+        assert_eq!(
+            VersionedResult::from(current).convert_to("0.2.0").unwrap(),
+            result
+        );
+    }
+
+    #[test]
+    fn test_v020_requires_ip() {
+        let result = VersionedResult::from(SuccessReply::default()).convert_to("0.2.0");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_v110_fields_dropped_for_older_versions() {
+        let data = br#"{
+            "cniVersion": "1.1.0",
+            "interfaces": [{"name": "eth0", "mtu": 1450}],
+            "routes": [{"dst": "0.0.0.0/0", "table": 100}]
+        }"#;
+        let result = VersionedResult::from_slice(data).unwrap();
+        let value = serde_json::to_value(result.clone().convert_to("1.1.0").unwrap()).unwrap();
+        assert_eq!(value["routes"][0]["table"], 100);
+
+        let value = serde_json::to_value(result.convert_to("1.0.0").unwrap()).unwrap();
+        assert_eq!(value["routes"][0].get("table"), None);
+        assert_eq!(value["interfaces"][0].get("mtu"), None);
+    }
+
+    #[test]
+    fn test_prev_result_in_net_conf() {
+        let conf = format!(
+            r#"{{"cniVersion": "0.4.0", "name": "mynet", "type": "tuning", "prevResult": {}}}"#,
+            RESULT_040
+        );
+        let conf: NetConf = serde_json::from_str(&conf).unwrap();
+        let prev_result = conf.prev_result.unwrap();
+        assert_eq!(prev_result.ips.len(), 2);
+        assert_eq!(prev_result.interfaces[1].name, "eth0");
+
+        let mut result = SuccessReply::from_prev(&conf.cni_version, Some(prev_result));
+        let index = result.add_interface(Interface {
+            name: "veth0".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(index, 2);
+        result.add_route(Route::new("10.0.0.0/8".parse().unwrap(), None));
+        assert_eq!(result.routes.len(), 3);
+
+        let (index, _) = result
+            .find_interface("eth0", Some(Path::new("/var/run/netns/test")))
+            .unwrap();
+        assert_eq!(result.ips_of(index).count(), 2);
+        assert!(result
+            .find_interface("eth0", Some(Path::new("/other")))
+            .is_none());
+    }
+
+    #[test]
+    fn test_guess_version_without_cni_version() {
+        let result = VersionedResult::from_slice(br#"{"ips": [{"address": "10.1.2.3/24"}]}"#);
+        assert!(matches!(result.unwrap(), VersionedResult::V100(_)));
+
+        let data = br#"{"ips": [{"version": "4", "address": "10.1.2.3/24"}]}"#;
+        let result = VersionedResult::from_slice(data);
+        assert!(matches!(result.unwrap(), VersionedResult::V040(_)));
+    }
+}
@@ -0,0 +1,33 @@
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Dns, Route};
+
+/// Result layout used by spec versions 0.1.0 and 0.2.0.
+///
+/// These versions know a single address per family and no interfaces, routes
+/// hang off the address they belong to.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Result {
+    #[serde(default)]
+    pub cni_version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip4: Option<IpConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip6: Option<IpConfig>,
+    #[serde(default, skip_serializing_if = "Dns::is_empty")]
+    pub dns: Dns,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IpConfig {
+    pub ip: IpNetwork,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Route>,
+}
@@ -1,9 +1,22 @@
 use std::fmt;
+use std::io::{stdin, stdout, Read};
 use std::str::FromStr;
 
 use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
+use crate::args;
+use crate::args::LoadArgs;
+use crate::config;
+use crate::error::{CniError, ErrorCode, ErrorReply};
+use crate::logger;
+use crate::logger::LogConfig;
 use crate::prelude::CniResult;
+use crate::result::VersionedResult;
+use crate::types::GcAttachment;
+use crate::version;
+use crate::version::PluginInfo;
 
 #[derive(Debug)]
 pub struct CmdArgs {
@@ -12,12 +25,25 @@ pub struct CmdArgs {
     pub if_name: String,
     pub args: String,
     pub path: String,
+    pub stdin_data: Vec<u8>,
+    /// Taken from `cni.dev/valid-attachments` for GC, empty otherwise.
+    pub valid_attachments: Vec<GcAttachment>,
 }
 
+impl CmdArgs {
+    /// Decode `CNI_ARGS` into `T`, see [`crate::args::load_args`].
+    pub fn load_args<T: LoadArgs>(&self) -> CniResult<T> {
+        args::load_args(&self.args)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Cmd {
     Add,
     Del,
     Check,
+    Status,
+    Gc,
     Version,
 }
 
@@ -27,6 +53,8 @@ impl fmt::Display for Cmd {
             Cmd::Add => write!(f, "ADD"),
             Cmd::Del => write!(f, "DEL"),
             Cmd::Check => write!(f, "CHECK"),
+            Cmd::Status => write!(f, "STATUS"),
+            Cmd::Gc => write!(f, "GC"),
             Cmd::Version => write!(f, "VERSION"),
         }
     }
@@ -40,49 +68,289 @@ impl FromStr for Cmd {
             "ADD" => Ok(Cmd::Add),
             "DEL" => Ok(Cmd::Del),
             "CHECK" => Ok(Cmd::Check),
+            "STATUS" => Ok(Cmd::Status),
+            "GC" => Ok(Cmd::Gc),
             "VERSION" => Ok(Cmd::Version),
             _ => Err(anyhow!("unknown command: {}", s)),
         }
     }
 }
 
-pub type CmdOutput = CniResult<()>;
-pub type CmdFn = fn(CmdArgs) -> CmdOutput;
-pub type PluginResult = CniResult<()>;
+pub type PluginResult = Result<(), CniError>;
+
+/// A CNI plugin driven by [`plugin_main`].
+///
+/// The skel reads stdin once, decodes it into `Config` and hands it to the
+/// method matching `CNI_COMMAND`. Whatever `add` returns is converted to the
+/// config's `cniVersion` and printed to stdout.
+pub trait CniPlugin {
+    /// Names the default log file and identifies the plugin to syslog.
+    const NAME: &'static str;
+
+    type Config: DeserializeOwned;
+    type Output: Into<VersionedResult>;
+
+    fn add(&self, args: &CmdArgs, config: Self::Config) -> CniResult<Self::Output>;
+
+    fn del(&self, args: &CmdArgs, config: Self::Config) -> CniResult<()>;
+
+    fn check(&self, args: &CmdArgs, config: Self::Config) -> CniResult<()>;
+
+    /// Whether the plugin is ready to service ADD, failing with
+    /// [`ErrorCode::PluginNotAvailable`] or [`ErrorCode::LimitedConnectivity`]
+    /// if not.
+    fn status(&self, _args: &CmdArgs, _config: Self::Config) -> CniResult<()> {
+        Ok(())
+    }
+
+    /// Release whatever is held for attachments not in `args.valid_attachments`.
+    fn gc(&self, _args: &CmdArgs, _config: Self::Config) -> CniResult<()> {
+        Ok(())
+    }
+}
+
+/// Run the command requested through `CNI_COMMAND`.
+///
+/// On failure the error is written to stdout as a CNI error result and the
+/// process exits with a non-zero status, so this only returns on success.
+pub fn plugin_main<P: CniPlugin>(plugin: P, version_info: PluginInfo) {
+    let (cmd, args) = match get_cmd_args_from_env() {
+        Ok(it) => it,
+        Err(e) => exit_with_error(version::CURRENT, e.into()),
+    };
+    init_logging(P::NAME, cmd, &args);
+    let cni_version =
+        version::config_version(&args.stdin_data).unwrap_or_else(|_| version::CURRENT.to_string());
+    if let Err(e) = plugin_main_with_error(&plugin, cmd, args, &version_info) {
+        exit_with_error(&cni_version, e);
+    }
+}
+
+// A plugin that cannot log should still do its job, so only complain on stderr.
+fn init_logging(name: &str, cmd: Cmd, args: &CmdArgs) {
+    let tag = if args.container_id.is_empty() {
+        cmd.to_string()
+    } else {
+        format!("{} {}", cmd, args.container_id)
+    };
+    let result = LogConfig::load(name, &args.stdin_data)
+        .and_then(|config| logger::init(&config, name, &tag));
+    if let Err(e) = result {
+        eprintln!("{}: failed to set up logging: {:#}", name, e);
+    }
+}
+
+fn plugin_main_with_error<P: CniPlugin>(
+    plugin: &P,
+    cmd: Cmd,
+    mut args: CmdArgs,
+    version_info: &PluginInfo,
+) -> PluginResult {
+    if let Cmd::Version = cmd {
+        return write_stdout(version_info);
+    }
+    let cni_version = check_version_compatible(&args.stdin_data, version_info)?;
+    if matches!(cmd, Cmd::Status | Cmd::Gc)
+        && !version::greater_than_or_equal(&cni_version, version::STATUS_GC_VERSION)?
+    {
+        return Err(CniError::new(
+            ErrorCode::IncompatibleCniVersion,
+            format!("config version does not allow {}", cmd),
+            format!(
+                "config is \"{}\", {} needs {} or later",
+                cni_version,
+                cmd,
+                version::STATUS_GC_VERSION
+            ),
+        ));
+    }
 
-pub fn plugin_main(add_fn: CmdFn, del_fn: CmdFn, check_fn: CmdFn) -> PluginResult {
-    let (cmd, args) = get_cmd_args_from_env()?;
+    let config: P::Config = serde_json::from_slice(&args.stdin_data).map_err(|e| {
+        CniError::new(
+            ErrorCode::DecodingFailure,
+            "failed to decode network config",
+            e.to_string(),
+        )
+    })?;
     match cmd {
         Cmd::Add => {
-            add_fn(args)?;
+            let output: VersionedResult = plugin.add(&args, config)?.into();
+            write_stdout(&output.convert_to(&cni_version)?)?;
         }
         Cmd::Del => {
-            del_fn(args)?;
+            plugin.del(&args, config)?;
         }
         Cmd::Check => {
-            check_fn(args)?;
+            plugin.check(&args, config)?;
         }
-        Cmd::Version => {
-            todo!()
+        Cmd::Status => {
+            plugin.status(&args, config)?;
         }
+        Cmd::Gc => {
+            args.valid_attachments = valid_attachments(&args.stdin_data)?;
+            plugin.gc(&args, config)?;
+        }
+        Cmd::Version => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn valid_attachments(stdin_data: &[u8]) -> CniResult<Vec<GcAttachment>> {
+    #[derive(serde::Deserialize)]
+    struct GcConf {
+        #[serde(rename = "cni.dev/valid-attachments", default)]
+        valid_attachments: Vec<GcAttachment>,
+    }
+    let conf: GcConf = serde_json::from_slice(stdin_data).map_err(|e| {
+        CniError::new(
+            ErrorCode::DecodingFailure,
+            "failed to decode cni.dev/valid-attachments",
+            e.to_string(),
+        )
+    })?;
+    Ok(conf.valid_attachments)
+}
+
+fn write_stdout<T: Serialize>(value: &T) -> PluginResult {
+    serde_json::to_writer(stdout(), value).map_err(|e| {
+        CniError::new(
+            ErrorCode::IoFailure,
+            "failed to write result to stdout",
+            e.to_string(),
+        )
+    })
+}
+
+fn exit_with_error(cni_version: &str, error: CniError) -> ! {
+    log::error!("plugin failed, code: {}, error: {}", error.code, error);
+    let reply = ErrorReply {
+        cni_version: cni_version.to_string(),
+        error,
+    };
+    let _ = serde_json::to_writer(stdout(), &reply);
+    std::process::exit(1);
+}
+
+fn check_version_compatible(
+    stdin_data: &[u8],
+    version_info: &PluginInfo,
+) -> Result<String, CniError> {
+    let config_version = version::config_version(stdin_data).map_err(|e| {
+        CniError::new(
+            ErrorCode::DecodingFailure,
+            "failed to decode network config",
+            e.to_string(),
+        )
+    })?;
+    if !version_info.supports(&config_version) {
+        return Err(CniError::new(
+            ErrorCode::IncompatibleCniVersion,
+            "incompatible CNI versions",
+            format!(
+                "config is \"{}\", plugin supports {:?}",
+                config_version, version_info.supported_versions
+            ),
+        ));
     }
+    Ok(config_version)
+}
+
+/// Linux refuses interface names of `IFNAMSIZ` (16) bytes or more, counting the NUL.
+const MAX_IF_NAME_LEN: usize = 15;
+
+// The variables each command cannot run without, CNI_ARGS is always optional.
+fn required_vars(cmd: &Cmd) -> &'static [&'static str] {
+    match cmd {
+        Cmd::Add | Cmd::Check => &["CNI_CONTAINERID", "CNI_NETNS", "CNI_IFNAME", "CNI_PATH"],
+        Cmd::Del => &["CNI_CONTAINERID", "CNI_IFNAME", "CNI_PATH"],
+        Cmd::Status | Cmd::Gc => &["CNI_PATH"],
+        Cmd::Version => &[],
+    }
+}
+
+fn check_container_id(container_id: &str) -> Result<(), String> {
+    if !config::is_valid_name(container_id) {
+        return Err(format!(
+            "invalid characters in CNI_CONTAINERID {:?}",
+            container_id
+        ));
+    }
+    Ok(())
+}
 
+fn check_if_name(if_name: &str) -> Result<(), String> {
+    if if_name.len() > MAX_IF_NAME_LEN {
+        return Err(format!(
+            "CNI_IFNAME {:?} is longer than {} bytes",
+            if_name, MAX_IF_NAME_LEN
+        ));
+    }
+    if if_name == "." || if_name == ".." {
+        return Err(format!("CNI_IFNAME {:?} is not a valid name", if_name));
+    }
+    if if_name
+        .chars()
+        .any(|c| c == '/' || c == ':' || c.is_whitespace())
+    {
+        return Err(format!(
+            "CNI_IFNAME {:?} contains / or : or whitespace characters",
+            if_name
+        ));
+    }
     Ok(())
 }
 
 pub fn get_cmd_args_from_env() -> CniResult<(Cmd, CmdArgs)> {
-    let cmd = std::env::var("CNI_COMMAND")
-        .unwrap_or("".into())
-        .as_str()
-        .parse()?;
-    let container_id = std::env::var("CNI_CONTAINERID").unwrap_or("".into());
-    let netns = std::env::var("CNI_NETNS").unwrap_or("".into());
-    let if_name = std::env::var("CNI_IFNAME").unwrap_or("".into());
-    let args = std::env::var("CNI_ARGS").unwrap_or("".into());
+    let (cmd, mut args) = cmd_args_from_vars(|key| std::env::var(key).ok())?;
+    stdin().read_to_end(&mut args.stdin_data)?;
+    Ok((cmd, args))
+}
+
+// Everything get_cmd_args_from_env does except reading stdin, so it can be
+// tested without touching the process environment.
+fn cmd_args_from_vars(var: impl Fn(&str) -> Option<String>) -> CniResult<(Cmd, CmdArgs)> {
+    let cmd = var("CNI_COMMAND")
+        .unwrap_or_default()
+        .parse::<Cmd>()
+        .map_err(|e| CniError::new(ErrorCode::InvalidEnvironmentVariables, e.to_string(), ""))?;
+    let container_id = var("CNI_CONTAINERID").unwrap_or_default();
+    let netns = var("CNI_NETNS").unwrap_or_default();
+    let if_name = var("CNI_IFNAME").unwrap_or_default();
+    let args = var("CNI_ARGS").unwrap_or_default();
 
     // List of paths to search for CNI plugin executables.
     // Paths are separated by an OS-specific list separator; for example ‘:’ on Linux and ‘;’ on Windows
-    let path = std::env::var("CNI_PATH").unwrap_or("".into());
+    let path = var("CNI_PATH").unwrap_or_default();
+
+    // Report every problem at once rather than making the runtime fix them one by one
+    let mut problems = vec![];
+    let missing = required_vars(&cmd)
+        .iter()
+        .filter(|key| var(key).unwrap_or_default().is_empty())
+        .copied()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        problems.push(format!(
+            "required env variables [{}] missing",
+            missing.join(",")
+        ));
+    }
+    if !container_id.is_empty() {
+        problems.extend(check_container_id(&container_id).err());
+    }
+    if !if_name.is_empty() {
+        problems.extend(check_if_name(&if_name).err());
+    }
+    if !problems.is_empty() {
+        return Err(CniError::new(
+            ErrorCode::InvalidEnvironmentVariables,
+            "invalid CNI environment",
+            problems.join("; "),
+        )
+        .into());
+    }
+
     Ok((
         cmd,
         CmdArgs {
@@ -91,6 +359,148 @@ pub fn get_cmd_args_from_env() -> CniResult<(Cmd, CmdArgs)> {
             if_name,
             args,
             path,
+            stdin_data: Vec::new(),
+            valid_attachments: vec![],
         },
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn from_vars(vars: &[(&str, &str)]) -> CniResult<(Cmd, CmdArgs)> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        cmd_args_from_vars(|key| vars.get(key).cloned())
+    }
+
+    fn env_error(vars: &[(&str, &str)]) -> CniError {
+        let err = CniError::from(from_vars(vars).unwrap_err());
+        assert_eq!(err.code, ErrorCode::InvalidEnvironmentVariables);
+        err
+    }
+
+    #[test]
+    fn test_required_vars_per_command() {
+        let (_, args) = from_vars(&[
+            ("CNI_COMMAND", "ADD"),
+            ("CNI_CONTAINERID", "abc-123"),
+            ("CNI_NETNS", "/var/run/netns/test"),
+            ("CNI_IFNAME", "eth0"),
+            ("CNI_PATH", "/opt/cni/bin"),
+        ])
+        .unwrap();
+        assert_eq!(args.container_id, "abc-123");
+        assert_eq!(args.netns, "/var/run/netns/test");
+
+        // DEL may run after the netns is already gone
+        assert!(from_vars(&[
+            ("CNI_COMMAND", "DEL"),
+            ("CNI_CONTAINERID", "abc"),
+            ("CNI_IFNAME", "eth0"),
+            ("CNI_PATH", "/opt/cni/bin"),
+        ])
+        .is_ok());
+        assert!(from_vars(&[("CNI_COMMAND", "VERSION")]).is_ok());
+        assert!(from_vars(&[("CNI_COMMAND", "GC"), ("CNI_PATH", "/opt/cni/bin")]).is_ok());
+        env_error(&[("CNI_COMMAND", "STATUS")]);
+
+        let err = env_error(&[("CNI_COMMAND", "CHECK"), ("CNI_NETNS", "")]);
+        assert_eq!(
+            err.details,
+            "required env variables [CNI_CONTAINERID,CNI_NETNS,CNI_IFNAME,CNI_PATH] missing"
+        );
+        env_error(&[("CNI_COMMAND", "BOGUS")]);
+    }
+
+    #[test]
+    fn test_every_problem_reported() {
+        let err = env_error(&[
+            ("CNI_COMMAND", "ADD"),
+            ("CNI_CONTAINERID", "-abc"),
+            ("CNI_IFNAME", "eth0:1"),
+            ("CNI_PATH", "/opt/cni/bin"),
+        ]);
+        assert!(err.details.contains("[CNI_NETNS]"));
+        assert!(err
+            .details
+            .contains("invalid characters in CNI_CONTAINERID"));
+        assert!(err.details.contains("contains / or :"));
+    }
+
+    #[derive(Default)]
+    struct GcRecorder {
+        collected: std::cell::RefCell<Vec<GcAttachment>>,
+    }
+
+    impl CniPlugin for GcRecorder {
+        const NAME: &'static str = "recorder";
+        type Config = crate::types::NetConf;
+        type Output = crate::types::SuccessReply;
+
+        fn add(&self, _: &CmdArgs, _: Self::Config) -> CniResult<Self::Output> {
+            unimplemented!()
+        }
+
+        fn del(&self, _: &CmdArgs, _: Self::Config) -> CniResult<()> {
+            unimplemented!()
+        }
+
+        fn check(&self, _: &CmdArgs, _: Self::Config) -> CniResult<()> {
+            unimplemented!()
+        }
+
+        fn gc(&self, args: &CmdArgs, _: Self::Config) -> CniResult<()> {
+            *self.collected.borrow_mut() = args.valid_attachments.clone();
+            Ok(())
+        }
+    }
+
+    fn run(plugin: &GcRecorder, cmd: &str, stdin: &str) -> PluginResult {
+        let (cmd, mut args) = from_vars(&[("CNI_COMMAND", cmd), ("CNI_PATH", "/opt/cni/bin")])?;
+        args.stdin_data = stdin.as_bytes().to_vec();
+        plugin_main_with_error(plugin, cmd, args, &PluginInfo::all())
+    }
+
+    #[test]
+    fn test_gc_and_status() {
+        let plugin = GcRecorder::default();
+        let stdin = r#"{
+            "cniVersion": "1.1.0",
+            "name": "net",
+            "type": "recorder",
+            "cni.dev/valid-attachments": [{"containerID": "abc", "ifname": "eth0"}]
+        }"#;
+        run(&plugin, "GC", stdin).unwrap();
+        assert_eq!(
+            *plugin.collected.borrow(),
+            vec![GcAttachment {
+                container_id: "abc".into(),
+                if_name: "eth0".into()
+            }]
+        );
+        run(&plugin, "STATUS", stdin).unwrap();
+
+        let old = r#"{"cniVersion": "1.0.0", "name": "net", "type": "recorder"}"#;
+        let err = run(&plugin, "STATUS", old).unwrap_err();
+        assert_eq!(err.code, ErrorCode::IncompatibleCniVersion);
+        assert_eq!(err.msg, "config version does not allow STATUS");
+    }
+
+    #[test]
+    fn test_check_if_name() {
+        assert!(check_if_name("eth0").is_ok());
+        assert!(check_if_name("a-fifteen-chars").is_ok());
+        assert!(check_if_name("sixteen-chars-xx").is_err());
+        assert!(check_if_name("..").is_err());
+        assert!(check_if_name("eth/0").is_err());
+        assert!(check_if_name("eth 0").is_err());
+        assert!(check_container_id("abc.def_1-2").is_ok());
+        assert!(check_container_id("abc/def").is_err());
+    }
+}
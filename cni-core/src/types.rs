@@ -2,7 +2,7 @@ use anyhow::bail;
 use std::collections::HashMap;
 use std::fmt;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use ipnetwork::IpNetwork;
@@ -11,10 +11,55 @@ use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+use crate::result::PrevResult;
+
+/// The keys every network configuration shares.
+///
+/// Meta plugins that only act on `prevResult` can use this as their whole
+/// config, others can `#[serde(flatten)]` it into their own.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetConf {
+    #[serde(default)]
+    pub cni_version: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub plugin: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub capabilities: HashMap<String, bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipam: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<Dns>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_result: Option<PrevResult>,
+    /// Only set for GC, the attachments the runtime still knows about.
+    #[serde(
+        rename = "cni.dev/valid-attachments",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub valid_attachments: Vec<GcAttachment>,
+}
+
+/// An attachment that GC must leave alone.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct GcAttachment {
+    #[serde(rename = "containerID")]
+    pub container_id: String,
+    #[serde(rename = "ifname")]
+    pub if_name: String,
+}
+
+/// A success result in the 1.x layout, the form plugins work with internally.
+///
+/// See [`crate::result::VersionedResult`] for conversion to older layouts.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SuccessReply {
     /// The CNI version of the plugin input config.
+    #[serde(default)]
     pub cni_version: String,
 
     /// The list of all interfaces created by this plugin.
@@ -39,6 +84,7 @@ pub struct SuccessReply {
     pub routes: Vec<Route>,
 
     /// Final DNS configuration for the namespace.
+    #[serde(default, skip_serializing_if = "Dns::is_empty")]
     pub dns: Dns,
 
     /// Custom reply fields.
@@ -48,7 +94,7 @@ pub struct SuccessReply {
     pub specific: HashMap<String, Value>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Ip {
     /// The IP address.
@@ -66,7 +112,7 @@ pub struct Ip {
     pub interface: Option<usize>, // None for ipam
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Interface {
     /// The name of the interface.
@@ -83,9 +129,71 @@ pub struct Interface {
     /// If the interface is on the host, this should be set to an empty string.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub sandbox: Option<PathBuf>,
+
+    /// The MTU of the interface (1.1.0 and later).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+
+    /// The path to a socket, for interfaces that are not kernel netdevs (1.1.0 and later).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_path: Option<String>,
+
+    /// The PCI address of the device backing the interface (1.1.0 and later).
+    #[serde(default, rename = "pciID", skip_serializing_if = "Option::is_none")]
+    pub pci_id: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+impl SuccessReply {
+    /// Start a result from `prevResult`, carrying its interfaces, IPs and
+    /// routes forward so this plugin only has to append its own.
+    pub fn from_prev(cni_version: &str, prev_result: Option<PrevResult>) -> Self {
+        let mut result = prev_result.map(PrevResult::into_inner).unwrap_or_default();
+        result.cni_version = cni_version.to_string();
+        result
+    }
+
+    /// Append an interface and return its index for [`Ip::interface`].
+    pub fn add_interface(&mut self, interface: Interface) -> usize {
+        self.interfaces.push(interface);
+        self.interfaces.len() - 1
+    }
+
+    pub fn add_ip(&mut self, ip: Ip) {
+        self.ips.push(ip);
+    }
+
+    pub fn add_route(&mut self, route: Route) {
+        self.routes.push(route);
+    }
+
+    /// Find an interface by name, and by sandbox when one is given.
+    pub fn find_interface(
+        &self,
+        name: &str,
+        sandbox: Option<&Path>,
+    ) -> Option<(usize, &Interface)> {
+        self.interfaces.iter().enumerate().find(|(_, it)| {
+            it.name == name && sandbox.is_none_or(|s| it.sandbox.as_deref() == Some(s))
+        })
+    }
+
+    /// The IPs assigned to the interface at `index`.
+    pub fn ips_of(&self, index: usize) -> impl Iterator<Item = &Ip> {
+        self.ips
+            .iter()
+            .filter(move |it| it.interface == Some(index))
+    }
+}
+
+impl Interface {
+    pub(crate) fn strip_v110_fields(&mut self) {
+        self.mtu = None;
+        self.socket_path = None;
+        self.pci_id = None;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Route {
     /// The destination of the route.
@@ -95,8 +203,50 @@ pub struct Route {
     ///
     /// If unset, a value in `gateway` in the `ips` array may be used by the
     /// runtime, but this is not mandated and is left to its discretion.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub gw: Option<IpAddr>,
+
+    /// The MTU for the route (1.1.0 and later).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+
+    /// The advertised MSS for the route (1.1.0 and later).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub advmss: Option<u32>,
+
+    /// The metric of the route (1.1.0 and later).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+
+    /// The routing table to add the route to (1.1.0 and later).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table: Option<u32>,
+
+    /// The scope of the route destination (1.1.0 and later).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<u8>,
+}
+
+impl Route {
+    pub fn new(dst: IpNetwork, gw: Option<IpAddr>) -> Self {
+        Self {
+            dst,
+            gw,
+            mtu: None,
+            advmss: None,
+            priority: None,
+            table: None,
+            scope: None,
+        }
+    }
+
+    pub(crate) fn strip_v110_fields(&mut self) {
+        self.mtu = None;
+        self.advmss = None;
+        self.priority = None;
+        self.table = None;
+        self.scope = None;
+    }
 }
 
 #[derive(Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
@@ -169,7 +319,7 @@ impl<'de> Deserialize<'de> for MacAddr {
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Dns {
     /// List of DNS nameservers this network is aware of.
@@ -196,6 +346,15 @@ pub struct Dns {
     pub options: Vec<String>,
 }
 
+impl Dns {
+    pub fn is_empty(&self) -> bool {
+        self.nameservers.is_empty()
+            && self.domain.is_none()
+            && self.search.is_empty()
+            && self.options.is_empty()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct IPAMArgs {
@@ -211,17 +370,3 @@ pub struct IPAMConfig {
     pub addresses: Option<Vec<Ip>>,
     pub dns: Option<Dns>,
 }
-
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct ExecResult {
-    #[serde(rename = "cniVersion")]
-    pub cni_version: Option<String>,
-    #[serde(rename = "interfaces", skip_serializing_if = "Option::is_none")]
-    pub interfaces: Option<Vec<Interface>>,
-    #[serde(rename = "ips")]
-    pub ips: Option<Vec<Ip>>,
-    #[serde(rename = "routes")]
-    pub routes: Option<Vec<Route>>,
-    #[serde(rename = "dns")]
-    pub dns: Option<Dns>,
-}
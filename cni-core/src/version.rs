@@ -0,0 +1,116 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+/// The spec version this library implements.
+pub const CURRENT: &str = "1.1.0";
+
+/// Every spec version a plugin built on this crate can speak.
+pub const ALL: &[&str] = &[
+    "0.1.0", "0.2.0", "0.3.0", "0.3.1", "0.4.0", "1.0.0", "1.1.0",
+];
+
+/// The first version with the STATUS and GC commands.
+pub const STATUS_GC_VERSION: &str = "1.1.0";
+
+/// Version reported when a network config omits `cniVersion`.
+pub const DEFAULT_CONFIG_VERSION: &str = "0.1.0";
+
+/// The document a plugin prints in answer to `CNI_COMMAND=VERSION`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginInfo {
+    pub cni_version: String,
+    pub supported_versions: Vec<String>,
+}
+
+impl PluginInfo {
+    pub fn new(supported_versions: &[&str]) -> Self {
+        Self {
+            cni_version: CURRENT.to_string(),
+            supported_versions: supported_versions.iter().map(|it| it.to_string()).collect(),
+        }
+    }
+
+    pub fn all() -> Self {
+        Self::new(ALL)
+    }
+
+    pub fn supports(&self, version: &str) -> bool {
+        self.supported_versions.iter().any(|it| it == version)
+    }
+}
+
+impl Default for PluginInfo {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+#[derive(Deserialize)]
+struct ConfigVersion {
+    #[serde(rename = "cniVersion", default)]
+    cni_version: String,
+}
+
+/// Extract `cniVersion` from raw network config bytes.
+///
+/// A config without the key is treated as 0.1.0, matching libcni.
+pub fn config_version(data: &[u8]) -> anyhow::Result<String> {
+    let conf: ConfigVersion = serde_json::from_slice(data)?;
+    if conf.cni_version.is_empty() {
+        return Ok(DEFAULT_CONFIG_VERSION.to_string());
+    }
+    Ok(conf.cni_version)
+}
+
+/// Split `major.minor.patch` into its numbers.
+pub fn parse(version: &str) -> anyhow::Result<(u64, u64, u64)> {
+    let parts = version
+        .split('.')
+        .map(|it| it.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow!("invalid version {:?}", version))?;
+    match parts[..] {
+        [major, minor, patch] => Ok((major, minor, patch)),
+        _ => Err(anyhow!("invalid version {:?}", version)),
+    }
+}
+
+pub fn greater_than_or_equal(version: &str, other: &str) -> anyhow::Result<bool> {
+    Ok(parse(version)? >= parse(other)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plugin_info_encode() {
+        let info = PluginInfo::new(&["0.4.0", "1.0.0"]);
+        assert_eq!(
+            serde_json::to_string(&info).unwrap(),
+            r#"{"cniVersion":"1.1.0","supportedVersions":["0.4.0","1.0.0"]}"#
+        );
+        assert!(info.supports("1.0.0"));
+        assert!(!info.supports("0.3.1"));
+    }
+
+    #[test]
+    fn test_config_version() {
+        assert_eq!(
+            config_version(br#"{"cniVersion": "0.3.1", "name": "a"}"#).unwrap(),
+            "0.3.1"
+        );
+        assert_eq!(config_version(br#"{"name": "a"}"#).unwrap(), "0.1.0");
+        assert!(config_version(b"not json").is_err());
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert!(greater_than_or_equal("1.1.0", "1.1.0").unwrap());
+        assert!(greater_than_or_equal("1.10.0", "1.2.0").unwrap());
+        assert!(!greater_than_or_equal("0.4.0", STATUS_GC_VERSION).unwrap());
+        assert!(greater_than_or_equal("1.0", "1.0.0").is_err());
+        assert!(parse("a.b.c").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail};
+use serde_json::Value;
+use sha2::{Digest, Sha512};
+
+use cni_core::args::parse_args;
+use cni_core::config::load_conf_list;
+use invoke::libcni::{CNIConfig, RuntimeConf};
+
+const ENV_CNI_PATH: &str = "CNI_PATH";
+const ENV_NET_DIR: &str = "NETCONFPATH";
+const ENV_CAPABILITY_ARGS: &str = "CAP_ARGS";
+const ENV_CNI_ARGS: &str = "CNI_ARGS";
+const ENV_CNI_IFNAME: &str = "CNI_IFNAME";
+
+const DEFAULT_NET_DIR: &str = "/etc/cni/net.d";
+const DEFAULT_IFNAME: &str = "eth0";
+const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+const TEMP_NETNS_FLAG: &str = "--temp-netns";
+
+fn usage() -> ! {
+    let exe = std::env::args().next().unwrap_or("cnitool".into());
+    eprintln!(
+        "{exe}: Add, check, or remove network interfaces from a network namespace
+  {exe} [{flag}] add   <net> <netns>
+  {exe} [{flag}] check <net> <netns>
+  {exe} [{flag}] del   <net> <netns>
+  {exe} gc     <net>
+  {exe} status <net>
+
+<netns> is a path, or the name of a namespace in {run_dir}. With {flag} add
+creates the named namespace and del removes it afterwards.
+
+Environment:
+  {ENV_CNI_PATH}      directories to look for plugins in, separated by ':'
+  {ENV_NET_DIR}   directory with the network configs, default {DEFAULT_NET_DIR}
+  {ENV_CAPABILITY_ARGS}      JSON object of capability args, e.g. {{\"portMappings\": [...]}}
+  {ENV_CNI_ARGS}      extra CNI_ARGS, as K1=V1;K2=V2
+  {ENV_CNI_IFNAME}    interface name in the namespace, default {DEFAULT_IFNAME}",
+        exe = exe,
+        flag = TEMP_NETNS_FLAG,
+        run_dir = NETNS_RUN_DIR,
+    );
+    std::process::exit(1)
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let temp_netns = match args.iter().position(|it| it == TEMP_NETNS_FLAG) {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+    if let Err(e) = run(&args, temp_netns) {
+        eprintln!("cnitool: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[String], temp_netns: bool) -> anyhow::Result<()> {
+    let (command, net_name) = match args {
+        [command, net_name, ..] => (command.as_str(), net_name.as_str()),
+        _ => usage(),
+    };
+    let net_dir = std::env::var(ENV_NET_DIR).unwrap_or(DEFAULT_NET_DIR.into());
+    let list = load_conf_list(&net_dir, net_name)?;
+    let config = CNIConfig::new(cni_path());
+
+    match command {
+        "gc" => return config.gc_network_list(&list, &[]),
+        "status" => return config.get_status(&list),
+        "add" | "check" | "del" => {}
+        _ => usage(),
+    }
+
+    let netns = match args.get(2) {
+        Some(netns) => netns_path(netns),
+        None => usage(),
+    };
+    let rt = RuntimeConf {
+        container_id: container_id(&netns),
+        netns: netns.to_string_lossy().to_string(),
+        if_name: std::env::var(ENV_CNI_IFNAME).unwrap_or(DEFAULT_IFNAME.into()),
+        args: cni_args()?,
+        capability_args: capability_args()?,
+        ..Default::default()
+    };
+
+    match command {
+        "add" => {
+            if temp_netns {
+                ip_netns("add", &netns)?;
+            }
+            let result = config.add_network_list(&list, &rt);
+            if result.is_err() && temp_netns {
+                // The ADD error is the one worth reporting
+                if let Err(e) = ip_netns("delete", &netns) {
+                    eprintln!("cnitool: {:#}", e);
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&result?)?);
+            Ok(())
+        }
+        "check" => config.check_network_list(&list, &rt),
+        _ => {
+            config.del_network_list(&list, &rt)?;
+            if temp_netns {
+                ip_netns("delete", &netns)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn cni_path() -> Vec<PathBuf> {
+    std::env::var(ENV_CNI_PATH)
+        .unwrap_or_default()
+        .split(':')
+        .filter(|it| !it.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn cni_args() -> anyhow::Result<Vec<(String, String)>> {
+    match std::env::var(ENV_CNI_ARGS) {
+        Ok(args) => parse_args(&args),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+fn capability_args() -> anyhow::Result<HashMap<String, Value>> {
+    match std::env::var(ENV_CAPABILITY_ARGS) {
+        Ok(args) => serde_json::from_str(&args)
+            .map_err(|e| anyhow!("invalid {}: {}", ENV_CAPABILITY_ARGS, e)),
+        Err(_) => Ok(HashMap::new()),
+    }
+}
+
+/// Resolve a bare namespace name to its path under [`NETNS_RUN_DIR`].
+fn netns_path(netns: &str) -> PathBuf {
+    if netns.contains('/') {
+        std::path::absolute(netns).unwrap_or(netns.into())
+    } else {
+        Path::new(NETNS_RUN_DIR).join(netns)
+    }
+}
+
+/// A stable container ID for the namespace, the same one Go's cnitool uses:
+/// the first 10 bytes of its SHA-512 in hex.
+fn container_id(netns: &Path) -> String {
+    let digest = Sha512::digest(netns.to_string_lossy().as_bytes());
+    let hex = digest[..10]
+        .iter()
+        .map(|it| format!("{:02x}", it))
+        .collect::<String>();
+    format!("cnitool-{}", hex)
+}
+
+/// Create or delete a namespace in [`NETNS_RUN_DIR`] with `ip netns`.
+fn ip_netns(action: &str, netns: &Path) -> anyhow::Result<()> {
+    if netns.parent() != Some(Path::new(NETNS_RUN_DIR)) {
+        bail!(
+            "{} only works with namespaces in {}",
+            TEMP_NETNS_FLAG,
+            NETNS_RUN_DIR
+        );
+    }
+    let name = netns
+        .file_name()
+        .ok_or(anyhow!("invalid namespace path {}", netns.display()))?
+        .to_string_lossy();
+    let output = Command::new("ip")
+        .args(["netns", action, name.as_ref()])
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "ip netns {} {} failed: {}",
+            action,
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netns_path() {
+        assert_eq!(netns_path("test"), Path::new("/var/run/netns/test"));
+        assert_eq!(netns_path("/proc/1/ns/net"), Path::new("/proc/1/ns/net"));
+    }
+
+    #[test]
+    fn test_ip_netns_rejects_bad_names() {
+        assert!(ip_netns("add", Path::new("/var/run/netns/..")).is_err());
+        assert!(ip_netns("add", Path::new("/proc/1/ns/net")).is_err());
+    }
+
+    #[test]
+    fn test_container_id() {
+        let id = container_id(Path::new("/var/run/netns/test"));
+        assert_eq!(id, "cnitool-7b8a61d5d8e94d07cbe4");
+        assert_eq!(id.len(), 28);
+        assert_ne!(id, container_id(Path::new("/var/run/netns/other")));
+    }
+}
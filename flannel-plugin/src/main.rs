@@ -3,35 +3,57 @@ extern crate log;
 extern crate simplelog;
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::stdout;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use ipnetwork::{Ipv4Network, Ipv6Network};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
 
-use cni_core::skel::CmdArgs;
-use cni_core::{logger, skel};
+use cni_core::result::PrevResult;
+use cni_core::skel;
+use cni_core::skel::{CmdArgs, CniPlugin};
+use cni_core::types::SuccessReply;
+use cni_core::version::PluginInfo;
+use invoke::{Exec, RawExec};
 
 const DEFAULT_SUBNET_FILE: &str = "/run/flannel/subnet.env";
 const DEFAULT_DATA_DIR: &str = "/var/lib/cni/flannel";
 
-fn main() -> anyhow::Result<()> {
-    logger::init("flannel-plugin.log")?;
-    skel::plugin_main(
-        |args| cmd_add(args),
-        |args| cmd_add(args),
-        |args| cmd_add(args),
-    )?;
-    Ok(())
+fn main() {
+    let plugin = FlannelPlugin {
+        exec: Box::new(RawExec::default()),
+    };
+    skel::plugin_main(plugin, PluginInfo::all());
+}
+
+struct FlannelPlugin {
+    exec: Box<dyn Exec>,
+}
+
+impl CniPlugin for FlannelPlugin {
+    const NAME: &'static str = "flannel";
+    type Config = NetConf;
+    type Output = SuccessReply;
+
+    fn add(&self, args: &CmdArgs, config: NetConf) -> anyhow::Result<SuccessReply> {
+        cmd_add(args, config, self.exec.as_ref())
+    }
+
+    fn del(&self, args: &CmdArgs, config: NetConf) -> anyhow::Result<()> {
+        cmd_del(args, config, self.exec.as_ref())
+    }
+
+    fn check(&self, args: &CmdArgs, config: NetConf) -> anyhow::Result<()> {
+        cmd_check(args, config, self.exec.as_ref())
+    }
 }
 
-fn cmd_add(cmd_args: CmdArgs) -> anyhow::Result<()> {
-    println!("cmd add ...............");
-    let mut net_conf = load_flannel_net_conf()?;
+fn cmd_add(cmd_args: &CmdArgs, net_conf: NetConf, exec: &dyn Exec) -> anyhow::Result<SuccessReply> {
+    let mut net_conf = load_flannel_net_conf(net_conf);
     let subnet_env = load_flannel_subnet_env(net_conf.subnet_file.as_ref().unwrap())?;
-    println!("subnet_env: {:?}", subnet_env);
+    info!("subnet_env: {:?}", subnet_env);
 
     match &net_conf.delegate {
         None => {
@@ -81,7 +103,7 @@ fn cmd_add(cmd_args: CmdArgs) -> anyhow::Result<()> {
     let delegate_mut = net_conf.delegate.as_mut().unwrap();
     delegate_mut.insert("ipam".into(), Value::Object(net_conf.ipam.clone().unwrap()));
 
-    println!(
+    info!(
         "delegate_conf: {}",
         serde_json::to_string_pretty(&net_conf.delegate)?
     );
@@ -90,21 +112,80 @@ fn cmd_add(cmd_args: CmdArgs) -> anyhow::Result<()> {
         &cmd_args.container_id,
         net_conf.data_dir.as_ref().unwrap(),
         net_conf.delegate.as_ref().unwrap(),
-    )?;
+        exec,
+        net_conf.delegate_timeout(),
+    )
+}
+
+fn cmd_del(cmd_args: &CmdArgs, net_conf: NetConf, exec: &dyn Exec) -> anyhow::Result<()> {
+    let net_conf = load_flannel_net_conf(net_conf);
+    let path = scratch_net_conf_path(&cmd_args.container_id, net_conf.data_dir.as_ref().unwrap());
+    let net_conf_bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        // Already cleaned up
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    match serde_json::from_slice::<cni_core::types::NetConf>(&net_conf_bytes) {
+        Ok(delegate) => invoke::delegate_del(
+            &delegate.plugin,
+            &net_conf_bytes,
+            true,
+            exec,
+            net_conf.delegate_timeout(),
+        )?,
+        // The interface will remain in the bridge until the node reboots
+        Err(e) => warn!("failed to parse netconf {}: {}", path.display(), e),
+    }
+    std::fs::remove_file(path)?;
     Ok(())
 }
 
+fn cmd_check(cmd_args: &CmdArgs, mut net_conf: NetConf, exec: &dyn Exec) -> anyhow::Result<()> {
+    let prev_result = net_conf
+        .prev_result
+        .take()
+        .ok_or(anyhow!("Required prevResult missing"))?;
+    let net_conf = load_flannel_net_conf(net_conf);
+    let path = scratch_net_conf_path(&cmd_args.container_id, net_conf.data_dir.as_ref().unwrap());
+    let net_conf_bytes = std::fs::read(&path)
+        .map_err(|e| anyhow!("failed to read netconf {}: {}", path.display(), e))?;
+
+    let delegate: cni_core::types::NetConf = serde_json::from_slice(&net_conf_bytes)?;
+    invoke::delegate_check(
+        &delegate.plugin,
+        &net_conf_bytes,
+        &prev_result,
+        exec,
+        net_conf.delegate_timeout(),
+    )
+}
+
 fn delegate_add(
-    _cid: &str,
-    _data_dir: &str,
+    cid: &str,
+    data_dir: &str,
     delegate_conf: &HashMap<String, Value>,
-) -> anyhow::Result<()> {
+    exec: &dyn Exec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<SuccessReply> {
     let net_conf_bytes = serde_json::to_string(&delegate_conf)?;
-    println!("net_conf_bytes: {}", net_conf_bytes);
+    info!("net_conf_bytes: {}", net_conf_bytes);
+
+    // save the rendered netconf for cmd_del and cmd_check
+    save_scratch_net_conf(cid, data_dir, net_conf_bytes.as_bytes())?;
 
     let plugin_type = delegate_conf.get("type").unwrap().as_str().unwrap();
-    let result = invoke::delegate_add(plugin_type, net_conf_bytes.as_bytes())?;
-    serde_json::to_writer(stdout(), &result).expect("writing to stdout should not fail");
+    invoke::delegate_add(plugin_type, net_conf_bytes.as_bytes(), exec, timeout)
+}
+
+fn scratch_net_conf_path(cid: &str, data_dir: &str) -> PathBuf {
+    Path::new(data_dir).join(cid)
+}
+
+fn save_scratch_net_conf(cid: &str, data_dir: &str, net_conf: &[u8]) -> anyhow::Result<()> {
+    std::fs::create_dir_all(data_dir)?;
+    std::fs::write(scratch_net_conf_path(cid, data_dir), net_conf)?;
     Ok(())
 }
 
@@ -130,18 +211,15 @@ fn get_delegate_ipam(n: &mut NetConf, subnet_env: &SubnetEnv) -> anyhow::Result<
         .map(|it| json!({"dst": it.to_string()}))
         .collect::<Vec<_>>();
     ipam.insert("routes".into(), Value::Array(routes));
-    println!("{}", serde_json::to_string(&ipam)?);
+    info!("{}", serde_json::to_string(&ipam)?);
 
     Ok(())
 }
 
-fn load_flannel_net_conf() -> anyhow::Result<NetConf> {
-    let f = File::open("/home/arthur/cni-rs/flannel.stdin.json")?;
-    // let mut n: NetConf = serde_json::from_reader(stdin())?;
-    let mut n: NetConf = serde_json::from_reader(&f)?;
+fn load_flannel_net_conf(mut n: NetConf) -> NetConf {
     n.subnet_file.get_or_insert(DEFAULT_SUBNET_FILE.into());
     n.data_dir.get_or_insert(DEFAULT_DATA_DIR.into());
-    Ok(n)
+    n
 }
 
 fn load_flannel_subnet_env(path: &str) -> anyhow::Result<SubnetEnv> {
@@ -207,6 +285,21 @@ pub struct NetConf {
     pub ipam: Option<Ipam>,
     #[serde(rename = "runtimeConfig", skip_serializing_if = "Option::is_none")]
     pub runtime_config: Option<HashMap<String, serde_json::Value>>,
+    /// Seconds a run of the delegate plugin may take before it is killed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(
+        rename = "prevResult",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub prev_result: Option<PrevResult>,
+}
+
+impl NetConf {
+    fn delegate_timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
 }
 
 pub type Ipam = Map<String, Value>;
@@ -223,8 +316,92 @@ pub struct SubnetEnv {
 
 #[cfg(test)]
 mod tests {
+    use invoke::FakeExec;
+
     use super::*;
 
+    fn setup() -> (tempfile::TempDir, NetConf) {
+        let dir = tempfile::tempdir().unwrap();
+        let subnet_file = dir.path().join("subnet.env");
+        std::fs::write(
+            &subnet_file,
+            "FLANNEL_NETWORK=10.244.0.0/16\n\
+             FLANNEL_SUBNET=10.244.1.1/24\n\
+             FLANNEL_MTU=1450\n\
+             FLANNEL_IPMASQ=true\n",
+        )
+        .unwrap();
+        let net_conf = serde_json::from_value(json!({
+            "cniVersion": "1.0.0",
+            "name": "cbr0",
+            "type": "flannel",
+            "subnetFile": subnet_file,
+            "dataDir": dir.path().join("data"),
+            "timeout": 30,
+        }))
+        .unwrap();
+        (dir, net_conf)
+    }
+
+    fn cmd_args() -> CmdArgs {
+        CmdArgs {
+            container_id: "ctr1".into(),
+            netns: "/var/run/netns/ctr1".into(),
+            if_name: "eth0".into(),
+            args: "".into(),
+            path: "/opt/cni/bin".into(),
+            stdin_data: vec![],
+            valid_attachments: vec![],
+        }
+    }
+
+    #[test]
+    fn test_delegate_to_bridge() {
+        let (dir, net_conf) = setup();
+        let exec = FakeExec::new().with_output(
+            "bridge",
+            "ADD",
+            json!({"cniVersion": "1.0.0", "ips": [{"address": "10.244.1.5/24"}]}),
+        );
+        let args = cmd_args();
+
+        let result = cmd_add(&args, net_conf.clone(), &exec).unwrap();
+        assert_eq!(result.ips[0].address.to_string(), "10.244.1.5/24");
+
+        let delegate = exec.calls()[0].net_conf();
+        assert_eq!(delegate["name"], "cbr0");
+        assert_eq!(delegate["type"], "bridge");
+        assert_eq!(delegate["cniVersion"], "1.0.0");
+        assert_eq!(delegate["ipMasq"], false);
+        assert_eq!(delegate["mtu"], 1450);
+        assert_eq!(delegate["isGateway"], true);
+        assert_eq!(delegate["ipam"]["type"], "host-local");
+        assert_eq!(delegate["ipam"]["ranges"][0][0]["subnet"], "10.244.1.0/24");
+        assert_eq!(delegate["ipam"]["routes"][0]["dst"], "10.244.0.0/16");
+        assert_eq!(exec.calls()[0].timeout, Some(Duration::from_secs(30)));
+
+        // CHECK and DEL go to the same delegate with the config saved by ADD
+        let mut check_conf = net_conf.clone();
+        check_conf.prev_result = Some(result.into());
+        cmd_check(&args, check_conf, &exec).unwrap();
+        let check = &exec.calls()[1];
+        assert_eq!(check.command, "CHECK");
+        assert_eq!(check.net_conf()["mtu"], 1450);
+        assert_eq!(
+            check.net_conf()["prevResult"]["ips"][0]["address"],
+            "10.244.1.5/24"
+        );
+
+        cmd_del(&args, net_conf.clone(), &exec).unwrap();
+        assert_eq!(exec.commands()[2], "bridge DEL");
+        assert!(exec.calls().iter().all(|it| it.timeout.is_some()));
+        assert!(!dir.path().join("data").join("ctr1").exists());
+
+        // A second DEL finds nothing to clean up
+        cmd_del(&args, net_conf, &exec).unwrap();
+        assert_eq!(exec.calls().len(), 3);
+    }
+
     #[test]
     fn test_1() {
         let ip_str = "172.17.78.1/24";
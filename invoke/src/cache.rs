@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use cni_core::prelude::CniResult;
+use cni_core::result::VersionedResult;
+
+/// The `kind` libcni tags its cache entries with.
+pub const CACHE_KIND: &str = "cniCacheV1";
+
+/// Everything recorded about one attachment, in libcni's on-disk layout.
+///
+/// The config is the one ADD ran with, so DEL and GC can still be run after
+/// the config file is gone.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedInfo {
+    pub kind: String,
+    pub container_id: String,
+    /// Raw config bytes, base64 encoded on disk like a Go `[]byte`.
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub config: Vec<u8>,
+    pub if_name: String,
+    pub network_name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub netns: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cni_args: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub capability_args: HashMap<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+}
+
+impl CachedInfo {
+    pub fn new(
+        network_name: &str,
+        container_id: &str,
+        if_name: &str,
+        config: &[u8],
+        result: &VersionedResult,
+    ) -> CniResult<Self> {
+        Ok(Self {
+            kind: CACHE_KIND.to_string(),
+            container_id: container_id.to_string(),
+            config: config.to_vec(),
+            if_name: if_name.to_string(),
+            network_name: network_name.to_string(),
+            result: Some(serde_json::to_value(result)?),
+            ..Default::default()
+        })
+    }
+
+    pub fn result(&self) -> CniResult<Option<VersionedResult>> {
+        match &self.result {
+            Some(result) => Ok(Some(VersionedResult::from_slice(&serde_json::to_vec(
+                result,
+            )?)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn to_base64<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let data = Option::<String>::deserialize(deserializer)?.unwrap_or_default();
+    STANDARD.decode(data).map_err(serde::de::Error::custom)
+}
+
+/// Results of ADD, kept so DEL and CHECK can hand them to plugins as
+/// `prevResult`.
+///
+/// Stored one file per attachment under `<dir>/results/`, in the same format
+/// as libcni so either can pick up the other's state.
+#[derive(Clone, Debug)]
+pub struct ResultCache {
+    dir: PathBuf,
+}
+
+impl ResultCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn results_dir(&self) -> PathBuf {
+        self.dir.join("results")
+    }
+
+    pub fn path(&self, network: &str, container_id: &str, if_name: &str) -> PathBuf {
+        self.results_dir()
+            .join(format!("{}-{}-{}", network, container_id, if_name))
+    }
+
+    /// The cached result, also from entries older libcni versions wrote as
+    /// a bare result.
+    pub fn get(
+        &self,
+        network: &str,
+        container_id: &str,
+        if_name: &str,
+    ) -> CniResult<Option<VersionedResult>> {
+        let data = match std::fs::read(self.path(network, container_id, if_name)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match serde_json::from_slice::<CachedInfo>(&data) {
+            Ok(info) if info.result.is_some() => info.result(),
+            _ => Ok(Some(VersionedResult::from_slice(&data)?)),
+        }
+    }
+
+    pub fn get_info(
+        &self,
+        network: &str,
+        container_id: &str,
+        if_name: &str,
+    ) -> CniResult<Option<CachedInfo>> {
+        match std::fs::read(self.path(network, container_id, if_name)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the entry through a temporary file, so readers never see a
+    /// partial one.
+    pub fn set(&self, info: &CachedInfo) -> CniResult<()> {
+        let path = self.path(&info.network_name, &info.container_id, &info.if_name);
+        let dir = self.results_dir();
+        std::fs::create_dir_all(&dir)?;
+
+        let tmp_path = dir.join(format!(
+            ".{}.tmp",
+            path.file_name().unwrap().to_string_lossy()
+        ));
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(info)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, network: &str, container_id: &str, if_name: &str) -> CniResult<()> {
+        match std::fs::remove_file(self.path(network, container_id, if_name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every cached attachment, or only those of `container_id`.
+    ///
+    /// Entries that are not in the current format are skipped.
+    pub fn list(&self, container_id: Option<&str>) -> CniResult<Vec<CachedInfo>> {
+        let entries = match std::fs::read_dir(self.results_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut infos = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if is_temp_file(&path) {
+                continue;
+            }
+            let info = match std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(serde_json::from_slice::<CachedInfo>(&data)?))
+            {
+                Ok(info) if info.kind == CACHE_KIND => info,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("skipping cache entry {}: {:#}", path.display(), e);
+                    continue;
+                }
+            };
+            if !matches!(container_id, Some(it) if it != info.container_id) {
+                infos.push(info);
+            }
+        }
+        Ok(infos)
+    }
+}
+
+fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|it| it.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn cache() -> (tempfile::TempDir, ResultCache) {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ResultCache::new(dir.path());
+        (dir, cache)
+    }
+
+    #[test]
+    fn test_libcni_format() {
+        let (_dir, cache) = cache();
+        // Written by libcni for a conflist ADD
+        let data = json!({
+            "kind": "cniCacheV1",
+            "containerId": "ctr1",
+            "config": "eyJuYW1lIjoibmV0In0=",
+            "ifName": "eth0",
+            "networkName": "net",
+            "netns": "/var/run/netns/ctr1",
+            "cniArgs": [["K8S_POD_NAME", "web"]],
+            "result": {"cniVersion": "1.0.0", "ips": [{"address": "10.0.0.2/24"}]}
+        });
+        let path = cache.path("net", "ctr1", "eth0");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, data.to_string()).unwrap();
+
+        let info = cache.get_info("net", "ctr1", "eth0").unwrap().unwrap();
+        assert_eq!(info.config, br#"{"name":"net"}"#);
+        assert_eq!(info.cni_args, vec![("K8S_POD_NAME".into(), "web".into())]);
+        let result = cache.get("net", "ctr1", "eth0").unwrap().unwrap();
+        assert_eq!(result.into_current().unwrap().ips.len(), 1);
+
+        cache.set(&info).unwrap();
+        let written: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn test_bare_result() {
+        let (_dir, cache) = cache();
+        let path = cache.path("net", "ctr1", "eth0");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, r#"{"cniVersion": "0.4.0", "ips": []}"#).unwrap();
+        assert!(cache.get("net", "ctr1", "eth0").unwrap().is_some());
+        assert!(cache.list(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list() {
+        let (_dir, cache) = cache();
+        assert!(cache.list(None).unwrap().is_empty());
+
+        let result = VersionedResult::from_slice(br#"{"cniVersion": "1.0.0"}"#).unwrap();
+        for (cid, if_name) in [("ctr1", "eth0"), ("ctr1", "net1"), ("ctr2", "eth0")] {
+            let info = CachedInfo::new("net", cid, if_name, b"{}", &result).unwrap();
+            cache.set(&info).unwrap();
+        }
+        assert_eq!(cache.list(None).unwrap().len(), 3);
+        assert_eq!(cache.list(Some("ctr1")).unwrap().len(), 2);
+
+        cache.delete("net", "ctr1", "eth0").unwrap();
+        cache.delete("net", "ctr1", "eth0").unwrap();
+        assert_eq!(cache.list(Some("ctr1")).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+/// Variables plugins get from the process running them, besides `CNI_*`.
+///
+/// Plugins need `PATH` to run tools like `iptables`, the rest only affect
+/// formatting and temporary files.
+pub const PASSTHROUGH_ENV: &[&str] = &["PATH", "HOME", "TMPDIR", "LANG", "LC_ALL", "TZ"];
+
+/// Builds a plugin's environment from scratch instead of copying the whole
+/// process environment.
+#[derive(Clone, Debug, Default)]
+pub struct EnvBuilder {
+    env: HashMap<String, String>,
+}
+
+impl EnvBuilder {
+    /// Start with the [`PASSTHROUGH_ENV`] variables of this process.
+    pub fn new() -> Self {
+        Self::default().passthrough(std::env::vars())
+    }
+
+    fn passthrough(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.env.extend(
+            vars.into_iter()
+                .filter(|(key, _)| PASSTHROUGH_ENV.contains(&key.as_str())),
+        );
+        self
+    }
+
+    /// Also pass on every `CNI_*` variable of this process, as a plugin does
+    /// when it delegates.
+    pub fn inherit_cni(self) -> Self {
+        self.inherit_cni_from(std::env::vars())
+    }
+
+    fn inherit_cni_from(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.env
+            .extend(vars.into_iter().filter(|(key, _)| key.starts_with("CNI_")));
+        self
+    }
+
+    pub fn set(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.env.insert(key.to_string(), value.into());
+        self
+    }
+
+    pub fn build(self) -> HashMap<String, String> {
+        self.env
+    }
+}
+
+/// Join `CNI_ARGS` pairs in the order given, as `K1=V1;K2=V2`.
+pub fn stringify_args(args: &[(String, String)]) -> String {
+    args.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_env_builder() {
+        let parent = vars(&[
+            ("PATH", "/usr/bin"),
+            ("AWS_SECRET_ACCESS_KEY", "secret"),
+            ("CNI_COMMAND", "ADD"),
+            ("CNI_NETNS", "/var/run/netns/a"),
+        ]);
+        let env = EnvBuilder::default()
+            .passthrough(parent.clone())
+            .set("CNI_COMMAND", "DEL")
+            .build();
+        assert_eq!(
+            env,
+            HashMap::from([
+                ("PATH".into(), "/usr/bin".into()),
+                ("CNI_COMMAND".into(), "DEL".into())
+            ])
+        );
+
+        let env = EnvBuilder::default()
+            .passthrough(parent.clone())
+            .inherit_cni_from(parent)
+            .set("CNI_COMMAND", "DEL")
+            .build();
+        assert_eq!(env["CNI_NETNS"], "/var/run/netns/a");
+        assert_eq!(env["CNI_COMMAND"], "DEL");
+        assert!(!env.contains_key("AWS_SECRET_ACCESS_KEY"));
+    }
+
+    #[test]
+    fn test_stringify_args() {
+        assert_eq!(stringify_args(&[]), "");
+        let args = vars(&[("K8S_POD_NAME", "web"), ("IgnoreUnknown", "true")]);
+        assert_eq!(stringify_args(&args), "K8S_POD_NAME=web;IgnoreUnknown=true");
+    }
+}
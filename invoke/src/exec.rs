@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::debug;
+use serde::Serialize;
+use serde_json::Value;
+use wait_timeout::ChildExt;
+
+use cni_core::error::{CniError, ErrorCode};
+use cni_core::version::PluginInfo;
+
+use crate::probe::probe;
+use crate::{find_exec_in_path, PluginNotFound};
+
+/// How plugins are found and run.
+///
+/// [`RawExec`] spawns real processes, [`FakeExec`] lets tests script plugin
+/// answers without any binaries.
+pub trait Exec: Send + Sync {
+    /// Run the plugin with `env` added to its environment and return its stdout.
+    ///
+    /// A plugin still running after `timeout` is killed and the run fails with
+    /// [`ErrorCode::TryAgainLater`]. Without a `timeout` the `Exec`'s own
+    /// default, if any, applies.
+    fn exec_plugin(
+        &self,
+        plugin_path: &Path,
+        stdin_data: &[u8],
+        env: &HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Vec<u8>>;
+
+    /// Find `plugin` in the first of `paths` that has it, failing with
+    /// [`PluginNotFound`].
+    fn find_in_path(&self, plugin: &str, paths: &[PathBuf]) -> anyhow::Result<PathBuf>;
+
+    /// Decode a plugin's answer to VERSION.
+    fn decode(&self, data: &[u8]) -> anyhow::Result<PluginInfo> {
+        Ok(serde_json::from_slice(data)?)
+    }
+
+    /// The versions the plugin supports, see [`probe`].
+    fn plugin_info(
+        &self,
+        plugin_path: &Path,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<PluginInfo> {
+        probe(plugin_path, self, timeout)
+    }
+}
+
+/// Runs plugins as child processes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RawExec {
+    /// Applies to runs that were not given a timeout of their own.
+    pub timeout: Option<Duration>,
+}
+
+impl RawExec {
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+        }
+    }
+}
+
+impl Exec for RawExec {
+    fn exec_plugin(
+        &self,
+        plugin_path: &Path,
+        stdin_data: &[u8],
+        env: &HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Vec<u8>> {
+        // stdout carries the plugin's result, so diagnostics only go to the log
+        debug!("plugin_path: {:?}", plugin_path);
+        debug!("env: {:?}", env);
+        debug!("stdin_data: {}", String::from_utf8_lossy(stdin_data));
+
+        let mut child = Command::new(plugin_path.as_os_str())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // The plugin gets `env` and nothing else from this process
+            .env_clear()
+            .envs(env)
+            .spawn()?;
+
+        // Feed and drain the pipes on their own threads so a plugin that fills
+        // one of them cannot stall the wait for its exit
+        let mut stdin = child.stdin.take().unwrap();
+        let stdin_data = stdin_data.to_vec();
+        let writer = thread::spawn(move || match stdin.write_all(&stdin_data) {
+            // A plugin may fail before reading its config, its output says why
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+            other => other,
+        });
+        let stdout = read_in_background(child.stdout.take().unwrap());
+        let stderr = read_in_background(child.stderr.take().unwrap());
+
+        let status = match timeout.or(self.timeout) {
+            Some(timeout) => match child.wait_timeout(timeout)? {
+                Some(status) => status,
+                None => {
+                    child.kill()?;
+                    child.wait()?;
+                    return Err(timed_out(plugin_path, timeout).into());
+                }
+            },
+            None => child.wait()?,
+        };
+        writer.join().expect("stdin writer panicked")?;
+        let stdout = stdout.join().expect("stdout reader panicked")?;
+        let stderr = stderr.join().expect("stderr reader panicked")?;
+
+        if !stderr.is_empty() {
+            debug!(
+                "{} stderr: {}",
+                plugin_path.display(),
+                String::from_utf8_lossy(&stderr)
+            );
+        }
+        if !status.success() {
+            return Err(plugin_error(status, &stdout, &stderr).into());
+        }
+        Ok(stdout)
+    }
+
+    fn find_in_path(&self, plugin: &str, paths: &[PathBuf]) -> anyhow::Result<PathBuf> {
+        find_exec_in_path(plugin, paths)
+    }
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut data = vec![];
+        pipe.read_to_end(&mut data)?;
+        Ok(data)
+    })
+}
+
+pub(crate) fn timed_out(plugin_path: &Path, timeout: Duration) -> CniError {
+    CniError::new(
+        ErrorCode::TryAgainLater,
+        format!(
+            "plugin {} timed out after {:?}",
+            plugin_path.display(),
+            timeout
+        ),
+        "",
+    )
+}
+
+/// Turn a failed run into the error the plugin reported.
+///
+/// A plugin prints a CNI error on stdout when it fails, which is passed on
+/// as is. A plugin that printed nothing is described by its stderr instead,
+/// and stderr also fills in the details of an error that has none.
+pub(crate) fn plugin_error(status: ExitStatus, stdout: &[u8], stderr: &[u8]) -> CniError {
+    let stderr = String::from_utf8_lossy(stderr).trim().to_string();
+    if stdout.iter().all(u8::is_ascii_whitespace) {
+        let msg = if stderr.is_empty() {
+            format!("netplugin failed with no error message: {}", status)
+        } else {
+            format!("netplugin failed: {:?}", stderr)
+        };
+        return CniError::new(ErrorCode::Internal, msg, "");
+    }
+
+    match serde_json::from_slice::<CniError>(stdout) {
+        Ok(mut error) => {
+            if error.details.is_empty() {
+                error.details = stderr;
+            }
+            error
+        }
+        Err(e) => CniError::new(
+            ErrorCode::Internal,
+            format!(
+                "netplugin failed but error parsing its diagnostic message {:?}: {}",
+                String::from_utf8_lossy(stdout),
+                e
+            ),
+            stderr,
+        ),
+    }
+}
+
+/// One plugin run recorded by [`FakeExec`].
+#[derive(Clone, Debug)]
+pub struct ExecCall {
+    pub plugin: String,
+    pub command: String,
+    pub stdin_data: Vec<u8>,
+    pub env: HashMap<String, String>,
+    pub timeout: Option<Duration>,
+}
+
+impl ExecCall {
+    /// The network config the plugin was given.
+    pub fn net_conf(&self) -> Value {
+        serde_json::from_slice(&self.stdin_data).unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug)]
+enum FakeResponse {
+    Output(Vec<u8>),
+    Error(CniError),
+}
+
+/// An [`Exec`] for tests that runs nothing.
+///
+/// Only registered plugins are found. A registered plugin answers with what
+/// was set up for the command, or succeeds with no output, and every run is
+/// recorded in [`FakeExec::calls`]. Version probes are answered without a run,
+/// from [`FakeExec::with_versions`] or else with every version.
+#[derive(Debug, Default)]
+pub struct FakeExec {
+    plugins: HashMap<String, HashMap<String, FakeResponse>>,
+    versions: HashMap<String, PluginInfo>,
+    calls: Mutex<Vec<ExecCall>>,
+}
+
+impl FakeExec {
+    pub const PLUGIN_DIR: &'static str = "/fake/cni/bin";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_plugin(mut self, plugin: &str) -> Self {
+        self.plugins.entry(plugin.to_string()).or_default();
+        self
+    }
+
+    /// Answer `command` by printing `output` as JSON.
+    pub fn with_output(mut self, plugin: &str, command: &str, output: impl Serialize) -> Self {
+        let output = serde_json::to_vec(&output).expect("fake output must serialize");
+        self.plugins
+            .entry(plugin.to_string())
+            .or_default()
+            .insert(command.to_string(), FakeResponse::Output(output));
+        self
+    }
+
+    /// Fail `command` with `error`.
+    pub fn with_error(mut self, plugin: &str, command: &str, error: CniError) -> Self {
+        self.plugins
+            .entry(plugin.to_string())
+            .or_default()
+            .insert(command.to_string(), FakeResponse::Error(error));
+        self
+    }
+
+    /// Have the plugin only support `versions`.
+    pub fn with_versions(mut self, plugin: &str, versions: &[&str]) -> Self {
+        self.plugins.entry(plugin.to_string()).or_default();
+        self.versions
+            .insert(plugin.to_string(), PluginInfo::new(versions));
+        self
+    }
+
+    pub fn calls(&self) -> Vec<ExecCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The commands run so far, as `"<plugin> <command>"`.
+    pub fn commands(&self) -> Vec<String> {
+        self.calls()
+            .iter()
+            .map(|it| format!("{} {}", it.plugin, it.command))
+            .collect()
+    }
+}
+
+fn plugin_name(plugin_path: &Path) -> String {
+    plugin_path
+        .file_name()
+        .map(|it| it.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+impl Exec for FakeExec {
+    fn exec_plugin(
+        &self,
+        plugin_path: &Path,
+        stdin_data: &[u8],
+        env: &HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let plugin = plugin_name(plugin_path);
+        let command = env.get("CNI_COMMAND").cloned().unwrap_or_default();
+        self.calls.lock().unwrap().push(ExecCall {
+            plugin: plugin.clone(),
+            command: command.clone(),
+            stdin_data: stdin_data.to_vec(),
+            env: env.clone(),
+            timeout,
+        });
+
+        match self.plugins.get(&plugin).and_then(|it| it.get(&command)) {
+            Some(FakeResponse::Output(output)) => Ok(output.clone()),
+            Some(FakeResponse::Error(error)) => Err(error.clone().into()),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn plugin_info(
+        &self,
+        plugin_path: &Path,
+        _timeout: Option<Duration>,
+    ) -> anyhow::Result<PluginInfo> {
+        let plugin = plugin_name(plugin_path);
+        Ok(self.versions.get(&plugin).cloned().unwrap_or_default())
+    }
+
+    fn find_in_path(&self, plugin: &str, paths: &[PathBuf]) -> anyhow::Result<PathBuf> {
+        if !self.plugins.contains_key(plugin) {
+            return Err(PluginNotFound::new(plugin, paths).into());
+        }
+        Ok(Path::new(Self::PLUGIN_DIR).join(plugin))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    /// A shell script plugin, removed again once the returned path is dropped.
+    pub(crate) fn script(name: &str, body: &str) -> tempfile::TempPath {
+        let mut file = tempfile::Builder::new().prefix(name).tempfile().unwrap();
+        write!(file, "#!/bin/sh\n{}\n", body).unwrap();
+        file.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        // Closed first, a file still open for writing cannot be run
+        file.into_temp_path()
+    }
+
+    fn run(path: &Path) -> anyhow::Result<Vec<u8>> {
+        RawExec::default().exec_plugin(path, b"{}", &HashMap::new(), None)
+    }
+
+    #[test]
+    fn test_success_keeps_stdout_only() {
+        let path = script("ok", r#"cat; echo "some noise" >&2"#);
+        assert_eq!(run(&path).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn test_env_is_not_inherited() {
+        std::env::set_var("INVOKE_EXEC_SECRET", "secret");
+        let path = script("env", r#"echo "$INVOKE_EXEC_SECRET:$CNI_COMMAND""#);
+        let env = crate::EnvBuilder::new().set("CNI_COMMAND", "ADD").build();
+        let output = RawExec::default()
+            .exec_plugin(&path, b"{}", &env, None)
+            .unwrap();
+        assert_eq!(output, b":ADD\n");
+    }
+
+    #[test]
+    fn test_error_json_is_decoded() {
+        let path = script(
+            "busy",
+            r#"echo '{"cniVersion": "1.0.0", "code": 11, "msg": "busy", "details": "lock held"}'
+exit 1"#,
+        );
+        let err = CniError::from(run(&path).unwrap_err());
+        assert_eq!(
+            err,
+            CniError::new(ErrorCode::TryAgainLater, "busy", "lock held")
+        );
+
+        let path = script(
+            "plugin-code",
+            r#"echo '{"code": 120, "msg": "no vlan"}'
+echo "vlan 5000 out of range" >&2
+exit 1"#,
+        );
+        let err = CniError::from(run(&path).unwrap_err());
+        assert_eq!(err.code, ErrorCode::Plugin(120));
+        assert_eq!(err.details, "vlan 5000 out of range");
+    }
+
+    #[test]
+    fn test_failure_without_error_json() {
+        let path = script("stderr-only", "echo 'cannot open netns' >&2\nexit 2");
+        let err = CniError::from(run(&path).unwrap_err());
+        assert_eq!(err.code, ErrorCode::Internal);
+        assert_eq!(err.msg, r#"netplugin failed: "cannot open netns""#);
+
+        let path = script("silent", "exit 3");
+        let err = CniError::from(run(&path).unwrap_err());
+        assert!(err
+            .msg
+            .starts_with("netplugin failed with no error message"));
+
+        let path = script("garbage", "echo 'not json'\nexit 1");
+        let err = CniError::from(run(&path).unwrap_err());
+        assert!(err.msg.contains("error parsing its diagnostic message"));
+
+        let path = script("killed", "kill -9 $$");
+        assert!(run(&path).is_err());
+    }
+
+    #[test]
+    fn test_timeout_kills_plugin() {
+        let path = script("hang", "sleep 10");
+        let started = std::time::Instant::now();
+        let err = RawExec::with_timeout(Duration::from_secs(10))
+            .exec_plugin(
+                &path,
+                b"{}",
+                &HashMap::new(),
+                Some(Duration::from_millis(100)),
+            )
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let err = CniError::from(err);
+        assert_eq!(err.code, ErrorCode::TryAgainLater);
+        assert!(err.msg.contains("timed out"));
+
+        let err = RawExec::with_timeout(Duration::from_millis(100))
+            .exec_plugin(&path, b"{}", &HashMap::new(), None)
+            .unwrap_err();
+        assert_eq!(CniError::from(err).code, ErrorCode::TryAgainLater);
+    }
+}
@@ -1,137 +1,580 @@
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::time::Duration;
 
-use log::info;
+use anyhow::bail;
+use log::{debug, info, warn};
+use serde_json::{Map, Value};
 
-use cni_core::types::ExecResult;
+use cni_core::result::VersionedResult;
+use cni_core::types::SuccessReply;
+use cni_core::version;
+
+pub mod cache;
+pub mod env;
+pub mod exec;
+pub mod libcni;
+pub mod nonblocking;
+pub mod probe;
+
+pub use env::{stringify_args, EnvBuilder};
+pub use exec::{Exec, ExecCall, FakeExec, RawExec};
 
 pub trait Args {
     fn as_env(&self) -> HashMap<String, String>;
 }
 
+/// The environment of a plugin run by a runtime.
+///
+/// Only the `CNI_*` variables set here and [`env::PASSTHROUGH_ENV`] reach the
+/// plugin.
+#[derive(Clone, Debug, Default)]
 pub struct CNIArgs {
     pub command: String,
     pub containerid: String,
     pub netns: String,
-    pub args: HashMap<String, String>,
+    /// `CNI_ARGS` pairs, passed on in this order.
+    pub args: Vec<(String, String)>,
     pub ifname: String,
     pub path: String,
 }
 
-fn stringify_args(args: &HashMap<String, String>) -> String {
-    let mut result = String::new();
-    for (key, value) in args {
-        result.push_str(&format!("{}={};", key, value));
-    }
-    result
-}
-
 impl Args for CNIArgs {
     fn as_env(&self) -> HashMap<String, String> {
-        let mut env = std::env::vars().collect::<HashMap<_, _>>();
-        env.insert("CNI_COMMAND".to_string(), self.command.clone());
-        env.insert("CNI_CONTAINERID".to_string(), self.containerid.clone());
-        env.insert("CNI_NETNS".to_string(), self.netns.clone());
-        env.insert("CNI_ARGS".to_string(), stringify_args(&self.args));
-        env.insert("CNI_IFNAME".to_string(), self.ifname.clone());
-        env.insert("CNI_PATH".to_string(), self.path.clone());
-        env
+        EnvBuilder::new()
+            .set("CNI_COMMAND", &self.command)
+            .set("CNI_CONTAINERID", &self.containerid)
+            .set("CNI_NETNS", &self.netns)
+            .set("CNI_ARGS", stringify_args(&self.args))
+            .set("CNI_IFNAME", &self.ifname)
+            .set("CNI_PATH", &self.path)
+            .build()
     }
 }
 
+/// The environment of a plugin run by another plugin, which gets the
+/// caller's `CNI_*` variables with only the command changed.
 pub struct DelegateArgs {
     pub command: String,
 }
 
 impl Args for DelegateArgs {
     fn as_env(&self) -> HashMap<String, String> {
-        let mut env = std::env::vars().collect::<HashMap<_, _>>();
-        env.insert("CNI_COMMAND".to_string(), self.command.clone());
-        env
+        EnvBuilder::new()
+            .inherit_cni()
+            .set("CNI_COMMAND", &self.command)
+            .build()
     }
 }
 
-pub fn delegate_add(plugin: &str, net_conf: &[u8]) -> anyhow::Result<ExecResult> {
-    let plugin_path = delegate_common(plugin)?;
+/// Returned when no `CNI_PATH` entry holds the plugin.
+#[derive(Debug, thiserror::Error)]
+#[error("plugin {plugin} not found in CNI_PATH: {path}")]
+pub struct PluginNotFound {
+    pub plugin: String,
+    /// The directories searched, separated by `:`.
+    pub path: String,
+}
+
+impl PluginNotFound {
+    pub fn new(plugin: &str, paths: &[PathBuf]) -> Self {
+        Self {
+            plugin: plugin.to_string(),
+            path: paths
+                .iter()
+                .filter(|it| !it.as_os_str().is_empty())
+                .map(|it| it.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(":"),
+        }
+    }
+}
+
+/// Run ADD on `plugin`.
+///
+/// `timeout` bounds every run of the plugin, including the version probe.
+/// Without one the default of `exec` applies.
+pub fn delegate_add(
+    plugin: &str,
+    net_conf: &[u8],
+    exec: &dyn Exec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<SuccessReply> {
+    let plugin_path = delegate_common(plugin, exec)?;
     info!("plugin_path: {:?}", plugin_path);
+    let net_conf = negotiate_version(&plugin_path, net_conf, exec, timeout)?;
     let res = exec_plugin_with_result(
+        exec,
         &plugin_path,
-        net_conf,
+        &net_conf,
         DelegateArgs {
             command: "ADD".to_string(),
         },
+        timeout,
     )?;
-    let result: ExecResult = serde_json::from_slice(&res)?;
+    VersionedResult::from_slice(&res)?.into_current()
+}
 
-    Ok(result)
+/// Like [`delegate_add`], but the ADD is undone with DEL if the returned
+/// guard is dropped before [`DelegatedAdd::commit`], e.g. because a later step
+/// of the caller's ADD failed.
+pub fn delegate_add_with_rollback<'a>(
+    plugin: &str,
+    net_conf: &[u8],
+    exec: &'a dyn Exec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<DelegatedAdd<'a>> {
+    let result = delegate_add(plugin, net_conf, exec, timeout)?;
+    Ok(DelegatedAdd {
+        plugin: plugin.to_string(),
+        net_conf: net_conf.to_vec(),
+        exec,
+        timeout,
+        result: Some(result),
+    })
 }
 
-pub fn delegate_common(plugin: &str) -> anyhow::Result<PathBuf> {
-    let cni_path = std::env::var("CNI_PATH").unwrap_or("".into());
-    info!("cni_path: {:?}", cni_path);
-    let paths = cni_path.split(':').map(Path::new).collect::<Vec<_>>();
+pub struct DelegatedAdd<'a> {
+    plugin: String,
+    net_conf: Vec<u8>,
+    exec: &'a dyn Exec,
+    timeout: Option<Duration>,
+    result: Option<SuccessReply>,
+}
+
+impl DelegatedAdd<'_> {
+    pub fn result(&self) -> &SuccessReply {
+        self.result.as_ref().unwrap()
+    }
+
+    /// Keep what the delegate set up and take its result.
+    pub fn commit(mut self) -> SuccessReply {
+        self.result.take().unwrap()
+    }
+}
+
+impl Drop for DelegatedAdd<'_> {
+    fn drop(&mut self) {
+        if self.result.is_none() {
+            return;
+        }
+        if let Err(e) = delegate_del(&self.plugin, &self.net_conf, true, self.exec, self.timeout) {
+            warn!("failed to roll back ADD of {}: {:#}", self.plugin, e);
+        }
+    }
+}
 
-    let plugin_exec_path = find_exec_in_path(plugin, paths).ok_or(anyhow::anyhow!(
-        "plugin {} not found in CNI_PATH: {}",
-        plugin,
-        cni_path
-    ))?;
+/// Run DEL on `plugin`.
+///
+/// With `ignore_not_found` a plugin missing from `CNI_PATH` is taken to have
+/// nothing to clean up, otherwise that is an error.
+pub fn delegate_del(
+    plugin: &str,
+    net_conf: &[u8],
+    ignore_not_found: bool,
+    exec: &dyn Exec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let plugin_path = match delegate_common(plugin, exec) {
+        Ok(plugin_path) => plugin_path,
+        Err(e) if ignore_not_found && e.is::<PluginNotFound>() => {
+            warn!("{}, skipping DEL", e);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let net_conf = negotiate_version(&plugin_path, net_conf, exec, timeout)?;
+    exec_plugin_with_result(
+        exec,
+        &plugin_path,
+        &net_conf,
+        DelegateArgs {
+            command: "DEL".to_string(),
+        },
+        timeout,
+    )?;
+    Ok(())
+}
 
-    Ok(plugin_exec_path)
+/// Run CHECK on `plugin` with `prev_result` set as the config's `prevResult`,
+/// converted to the config's `cniVersion`.
+pub fn delegate_check(
+    plugin: &str,
+    net_conf: &[u8],
+    prev_result: &SuccessReply,
+    exec: &dyn Exec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let plugin_path = delegate_common(plugin, exec)?;
+    let net_conf = negotiate_version(&plugin_path, net_conf, exec, timeout)?;
+    exec_plugin_with_result(
+        exec,
+        &plugin_path,
+        &with_prev_result(&net_conf, prev_result)?,
+        DelegateArgs {
+            command: "CHECK".to_string(),
+        },
+        timeout,
+    )?;
+    Ok(())
 }
 
-fn exec_plugin_with_result(
+/// Run GC on `plugin`. The config is passed on as is, so it keeps the
+/// caller's `cni.dev/valid-attachments`.
+///
+/// A plugin too old to know GC is skipped.
+pub fn delegate_gc(
+    plugin: &str,
+    net_conf: &[u8],
+    exec: &dyn Exec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    delegate_status_gc(plugin, net_conf, exec, timeout, "GC")
+}
+
+/// Run STATUS on `plugin`. A plugin too old to know STATUS is skipped.
+pub fn delegate_status(
+    plugin: &str,
+    net_conf: &[u8],
+    exec: &dyn Exec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    delegate_status_gc(plugin, net_conf, exec, timeout, "STATUS")
+}
+
+fn delegate_status_gc(
+    plugin: &str,
+    net_conf: &[u8],
+    exec: &dyn Exec,
+    timeout: Option<Duration>,
+    command: &str,
+) -> anyhow::Result<()> {
+    let plugin_path = delegate_common(plugin, exec)?;
+    let net_conf = negotiate_version(&plugin_path, net_conf, exec, timeout)?;
+    let cni_version = version::config_version(&net_conf)?;
+    if !version::greater_than_or_equal(&cni_version, version::STATUS_GC_VERSION)? {
+        debug!(
+            "{} only supports {}, skipping {}",
+            plugin, cni_version, command
+        );
+        return Ok(());
+    }
+    exec_plugin_with_result(
+        exec,
+        &plugin_path,
+        &net_conf,
+        DelegateArgs {
+            command: command.to_string(),
+        },
+        timeout,
+    )?;
+    Ok(())
+}
+
+pub(crate) fn with_prev_result(
+    net_conf: &[u8],
+    prev_result: &SuccessReply,
+) -> anyhow::Result<Vec<u8>> {
+    let cni_version = version::config_version(net_conf)?;
+    let prev_result = VersionedResult::from(prev_result.clone()).convert_to(&cni_version)?;
+    let mut conf: Map<String, Value> = serde_json::from_slice(net_conf)?;
+    conf.insert("prevResult".into(), serde_json::to_value(prev_result)?);
+    Ok(serde_json::to_vec(&conf)?)
+}
+
+/// Probe the plugin and rewrite `net_conf` to a `cniVersion` it supports.
+fn negotiate_version(
+    plugin_path: &Path,
+    net_conf: &[u8],
+    exec: &dyn Exec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Vec<u8>> {
+    let info = exec.plugin_info(plugin_path, timeout)?;
+    probe::with_negotiated_version(plugin_path, net_conf, &info)
+}
+
+pub fn delegate_common(plugin: &str, exec: &dyn Exec) -> anyhow::Result<PathBuf> {
+    let cni_path = std::env::var("CNI_PATH").unwrap_or_default();
+    info!("cni_path: {:?}", cni_path);
+    let paths = std::env::split_paths(&cni_path).collect::<Vec<_>>();
+    exec.find_in_path(plugin, &paths)
+}
+
+/// Run the plugin at `plugin_path` with the environment `args` describe.
+pub fn exec_plugin_with_result(
+    exec: &dyn Exec,
     plugin_path: &Path,
     stdin_data: &[u8],
     args: impl Args,
+    timeout: Option<Duration>,
 ) -> anyhow::Result<Vec<u8>> {
-    println!("plugin_path: {:?}", plugin_path);
-    println!("env: {:?}", args.as_env());
-    println!("stdin_data: {}", std::str::from_utf8(stdin_data).unwrap());
-
-    let mut child = Command::new(plugin_path.as_os_str())
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .envs(args.as_env())
-        .spawn()?;
-    let mut stdin = child.stdin.take().unwrap();
-    stdin.write_all(stdin_data)?;
-    drop(stdin);
-    let mut stdout = child.stdout.take().unwrap();
-    let mut buffer = Vec::new();
-    stdout.read_to_end(&mut buffer)?;
-    let exit_status = child.wait()?;
-
-    if let Some(code) = exit_status.code() {
-        if code != 0 {
-            println!("{}", exit_status.to_string());
-            println!("{}", std::str::from_utf8(&buffer).unwrap());
-            return Err(anyhow::anyhow!(
-                "plugin exited with non-zero exit code: {}",
-                code
-            ));
-        }
+    exec.exec_plugin(plugin_path, stdin_data, &args.as_env(), timeout)
+}
+
+/// Find `plugin` in the first of `paths` that holds an executable file of
+/// that name.
+///
+/// All directories are probed in parallel, so a slow mount in `CNI_PATH`
+/// does not hold up the others, but the earliest match still wins.
+/// Empty entries are skipped instead of meaning the current directory.
+/// Symlinks are followed for the checks, but the link itself is returned as
+/// multi-call binaries pick the plugin to run by the name they were run as.
+pub fn find_exec_in_path(plugin: &str, paths: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    if plugin.is_empty() {
+        bail!("no plugin name provided");
+    }
+    if plugin.contains(std::path::MAIN_SEPARATOR) {
+        bail!("invalid plugin name: {}", plugin);
+    }
+    let candidates = paths
+        .iter()
+        .filter(|it| !it.as_os_str().is_empty())
+        .map(|it| it.join(plugin))
+        .collect::<Vec<_>>();
+    let found = std::thread::scope(|scope| {
+        let probes = candidates
+            .iter()
+            .map(|it| scope.spawn(move || is_executable_file(it)))
+            .collect::<Vec<_>>();
+        probes
+            .into_iter()
+            .map(|it| it.join().unwrap_or(false))
+            .collect::<Vec<_>>()
+    });
+    match candidates.into_iter().zip(found).find(|(_, found)| *found) {
+        Some((full_path, _)) => Ok(full_path),
+        None => Err(PluginNotFound::new(plugin, paths).into()),
     }
-    Ok(buffer)
 }
 
-fn find_exec_in_path(plugin: &str, paths: Vec<&Path>) -> Option<PathBuf> {
-    for path in paths {
-        let full_path = path.join(plugin);
-        if full_path.exists() && full_path.is_file() {
-            return Some(full_path);
+fn is_executable_file(path: &Path) -> bool {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 => true,
+        Ok(_) => {
+            debug!("{} is not an executable file", path.display());
+            false
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => {
+            debug!("cannot use {}: {}", path.display(), e);
+            false
         }
     }
-    None
 }
 
 #[cfg(test)]
 mod tests {
     use log::info;
+    use serde_json::json;
+
+    use crate::*;
+
+    #[test]
+    fn test_with_prev_result() {
+        let prev_result = SuccessReply {
+            cni_version: "1.0.0".into(),
+            ips: vec![cni_core::types::Ip {
+                address: "10.0.0.2/24".parse().unwrap(),
+                gateway: None,
+                interface: None,
+            }],
+            ..Default::default()
+        };
+        let conf = with_prev_result(
+            br#"{"cniVersion": "0.4.0", "name": "net", "type": "static"}"#,
+            &prev_result,
+        )
+        .unwrap();
+        let conf: Value = serde_json::from_slice(&conf).unwrap();
+        assert_eq!(conf["type"], "static");
+        assert_eq!(
+            conf["prevResult"],
+            json!({
+                "cniVersion": "0.4.0",
+                "ips": [{"version": "4", "address": "10.0.0.2/24"}]
+            })
+        );
+    }
+
+    #[test]
+    fn test_delegate_del_plugin_not_found() {
+        let conf = br#"{"cniVersion": "1.0.0", "name": "net", "type": "missing"}"#;
+        let exec = FakeExec::new();
+        assert!(delegate_del("no-such-plugin", conf, true, &exec, None).is_ok());
+        let err = delegate_del("no-such-plugin", conf, false, &exec, None).unwrap_err();
+        assert!(err.is::<PluginNotFound>());
+        assert!(exec.calls().is_empty());
+    }
+
+    #[test]
+    fn test_delegate_check_passes_prev_result() {
+        let exec = FakeExec::new().with_plugin("static");
+        let prev_result = SuccessReply {
+            cni_version: "1.0.0".into(),
+            ..Default::default()
+        };
+        let conf = br#"{"cniVersion": "1.0.0", "name": "net", "type": "static"}"#;
+        delegate_check("static", conf, &prev_result, &exec, None).unwrap();
+
+        let calls = exec.calls();
+        assert_eq!(calls[0].command, "CHECK");
+        assert_eq!(calls[0].net_conf()["prevResult"]["cniVersion"], "1.0.0");
+    }
+
+    #[test]
+    fn test_delegate_gc_and_status() {
+        let conf = br#"{"cniVersion": "1.1.0", "name": "net", "type": "bridge",
+            "cni.dev/valid-attachments": [{"containerID": "ctr1", "ifname": "eth0"}]}"#;
+        let exec = FakeExec::new()
+            .with_plugin("host-local")
+            .with_versions("old", &["0.4.0", "1.0.0"]);
+        delegate_gc("host-local", conf, &exec, None).unwrap();
+        delegate_status("host-local", conf, &exec, None).unwrap();
+        let calls = exec.calls();
+        assert_eq!(exec.commands(), vec!["host-local GC", "host-local STATUS"]);
+        assert_eq!(
+            calls[0].net_conf()["cni.dev/valid-attachments"][0]["containerID"],
+            "ctr1"
+        );
 
-    use crate::delegate_add;
+        delegate_gc("old", conf, &exec, None).unwrap();
+        delegate_status("old", conf, &exec, None).unwrap();
+        assert_eq!(exec.commands().len(), 2);
+    }
+
+    #[test]
+    fn test_delegate_add_rollback() {
+        let result = SuccessReply {
+            cni_version: "1.0.0".into(),
+            ..Default::default()
+        };
+        let exec = FakeExec::new().with_output("static", "ADD", &result);
+        let conf = br#"{"cniVersion": "1.0.0", "name": "net", "type": "static"}"#;
+
+        let added = delegate_add_with_rollback("static", conf, &exec, None).unwrap();
+        assert_eq!(added.result(), &result);
+        drop(added);
+        assert_eq!(exec.commands(), vec!["static ADD", "static DEL"]);
+
+        let added = delegate_add_with_rollback("static", conf, &exec, None).unwrap();
+        assert_eq!(added.commit(), result);
+        assert_eq!(exec.commands().len(), 3);
+    }
+
+    #[test]
+    fn test_delegate_negotiates_version() {
+        let conf = br#"{"cniVersion": "1.1.0", "name": "net", "type": "old"}"#;
+        let exec = FakeExec::new()
+            .with_versions("old", &["0.3.1", "0.4.0"])
+            .with_output(
+                "old",
+                "ADD",
+                json!({"cniVersion": "0.4.0", "ips": [{"version": "4", "address": "10.0.0.2/24"}]}),
+            );
+        let result = delegate_add("old", conf, &exec, None).unwrap();
+        assert_eq!(result.ips.len(), 1);
+        assert_eq!(exec.calls()[0].net_conf()["cniVersion"], "0.4.0");
+
+        let conf = br#"{"cniVersion": "0.2.0", "name": "net", "type": "old"}"#;
+        let err =
+            cni_core::error::CniError::from(delegate_add("old", conf, &exec, None).unwrap_err());
+        assert_eq!(err.code, cni_core::error::ErrorCode::IncompatibleCniVersion);
+        assert_eq!(exec.commands(), vec!["old ADD"]);
+    }
+
+    #[test]
+    fn test_delegate_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let plugin = dir.path().join("slow");
+        std::fs::write(
+            &plugin,
+            r#"#!/bin/sh
+if [ "$CNI_COMMAND" = VERSION ]; then
+    echo '{"cniVersion": "1.0.0", "supportedVersions": ["1.0.0"]}'
+    exit 0
+fi
+exec sleep 10
+"#,
+        )
+        .unwrap();
+        std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CNI_PATH", dir.path());
+
+        let conf = br#"{"cniVersion": "1.0.0", "name": "net", "type": "slow"}"#;
+        let started = std::time::Instant::now();
+        let err = delegate_add(
+            "slow",
+            conf,
+            &RawExec::default(),
+            Some(Duration::from_millis(200)),
+        )
+        .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let err = cni_core::error::CniError::from(err);
+        assert_eq!(err.code, cni_core::error::ErrorCode::TryAgainLater);
+        assert_eq!(u32::from(err.code), 11);
+    }
+
+    #[test]
+    fn test_cni_args_env() {
+        let args = CNIArgs {
+            command: "ADD".into(),
+            containerid: "ctr1".into(),
+            args: vec![("B".into(), "2".into()), ("A".into(), "1".into())],
+            path: "/opt/cni/bin".into(),
+            ..Default::default()
+        };
+        let env = args.as_env();
+        assert_eq!(env["CNI_ARGS"], "B=2;A=1");
+        assert_eq!(env["CNI_PATH"], "/opt/cni/bin");
+        assert!(env
+            .keys()
+            .all(|it| it.starts_with("CNI_") || env::PASSTHROUGH_ENV.contains(&it.as_str())));
+    }
+
+    #[test]
+    fn test_find_exec_in_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (plain, bin) = (dir.join("plain"), dir.join("bin"));
+        std::fs::create_dir_all(&plain).unwrap();
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::write(plain.join("bridge"), "").unwrap();
+        std::fs::write(bin.join("multi"), "").unwrap();
+        std::fs::set_permissions(bin.join("multi"), std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        std::os::unix::fs::symlink(bin.join("multi"), bin.join("bridge")).unwrap();
+        std::os::unix::fs::symlink(bin.join("missing"), bin.join("dangling")).unwrap();
+
+        let paths = vec![PathBuf::new(), plain.clone(), bin.clone()];
+        // The non-executable copy in `plain` is passed over, the link is kept
+        assert_eq!(
+            find_exec_in_path("bridge", &paths).unwrap(),
+            bin.join("bridge")
+        );
+
+        let err = find_exec_in_path("dangling", &paths).unwrap_err();
+        let err = err.downcast::<PluginNotFound>().unwrap();
+        assert_eq!(err.path, format!("{}:{}", plain.display(), bin.display()));
+
+        // Probed in parallel, but the earlier directory still wins
+        let later = dir.join("later");
+        std::fs::create_dir_all(&later).unwrap();
+        std::fs::copy(bin.join("multi"), later.join("multi")).unwrap();
+        assert_eq!(
+            find_exec_in_path("multi", &[later.clone(), bin.clone()]).unwrap(),
+            later.join("multi")
+        );
+        assert_eq!(
+            find_exec_in_path("multi", &[bin.clone(), later]).unwrap(),
+            bin.join("multi")
+        );
+
+        assert!(find_exec_in_path("", &paths).is_err());
+        assert!(find_exec_in_path("../bin/multi", &paths).is_err());
+        assert!(find_exec_in_path("multi", &[PathBuf::new()]).is_err());
+    }
 
     #[test]
     fn test_run_plugin() {
@@ -160,7 +603,20 @@ mod tests {
   }
 }
        "#;
-        let a = delegate_add("static", net_conf.as_bytes()).unwrap();
+        let exec = FakeExec::new().with_output(
+            "static",
+            "ADD",
+            json!({
+                "cniVersion": "1.0.0",
+                "ips": [
+                    {"address": "10.10.0.1/24", "gateway": "10.10.0.254"},
+                    {"address": "3ffe:ffff:0:01ff::1/64", "gateway": "3ffe:ffff:0::1"}
+                ]
+            }),
+        );
+        let a = delegate_add("static", net_conf.as_bytes(), &exec, None).unwrap();
         info!("a: {:?}", a);
+        assert_eq!(a.ips.len(), 2);
+        assert_eq!(exec.calls()[0].net_conf()["ipam"]["type"], "static");
     }
 }
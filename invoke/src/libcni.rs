@@ -0,0 +1,623 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use serde_json::{Map, Value};
+
+use cni_core::config::{validate_network_name, NetworkConfig, NetworkConfigList};
+use cni_core::prelude::CniResult;
+use cni_core::result::VersionedResult;
+use cni_core::types::GcAttachment;
+use cni_core::version;
+use cni_core::version::PluginInfo;
+
+use crate::cache::{CachedInfo, ResultCache};
+use crate::exec::{Exec, RawExec};
+use crate::{exec_plugin_with_result, CNIArgs};
+
+pub const DEFAULT_CACHE_DIR: &str = "/var/lib/cni";
+
+/// The per-attachment half of an invocation, everything that is not in the
+/// network config.
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeConf {
+    pub container_id: String,
+    pub netns: String,
+    pub if_name: String,
+    /// Extra `CNI_ARGS` pairs.
+    pub args: Vec<(String, String)>,
+    /// Values for the `runtimeConfig` keys plugins declare in `capabilities`.
+    pub capability_args: HashMap<String, Value>,
+    /// Overrides [`CNIConfig::cache_dir`] for this attachment.
+    pub cache_dir: Option<PathBuf>,
+    /// How long each plugin may run before it is killed, see
+    /// [`Exec::exec_plugin`].
+    pub timeout: Option<Duration>,
+    /// Overrides [`CNIConfig::path`] for this attachment, both to find
+    /// plugins and as the `CNI_PATH` they get.
+    pub cni_path: Option<Vec<PathBuf>>,
+}
+
+/// Runs network config lists the way libcni does.
+///
+/// Plugins are looked up in `path`, and the result of every ADD is cached
+/// under `cache_dir` so DEL and CHECK can pass it back as `prevResult`.
+#[derive(Clone)]
+pub struct CNIConfig {
+    pub path: Vec<PathBuf>,
+    pub cache_dir: PathBuf,
+    exec: Arc<dyn Exec>,
+}
+
+impl fmt::Debug for CNIConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CNIConfig")
+            .field("path", &self.path)
+            .field("cache_dir", &self.cache_dir)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CNIConfig {
+    pub fn new(path: Vec<PathBuf>) -> Self {
+        Self::with_cache_dir(path, DEFAULT_CACHE_DIR)
+    }
+
+    pub fn with_cache_dir(path: Vec<PathBuf>, cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            path,
+            cache_dir: cache_dir.into(),
+            exec: Arc::new(RawExec::default()),
+        }
+    }
+
+    /// Run plugins through `exec` instead of spawning them.
+    pub fn with_exec(mut self, exec: Arc<dyn Exec>) -> Self {
+        self.exec = exec;
+        self
+    }
+
+    /// Run ADD for every plugin in order, feeding each one the result of the
+    /// one before, and cache the final result.
+    pub fn add_network_list(
+        &self,
+        list: &NetworkConfigList,
+        rt: &RuntimeConf,
+    ) -> CniResult<VersionedResult> {
+        validate_network_name(&list.name)?;
+        let cni_version = list_version(list);
+
+        let mut prev_result: Option<VersionedResult> = None;
+        for net in &list.plugins {
+            let result = self
+                .add_network(&list.name, cni_version, net, prev_result.as_ref(), rt)
+                .map_err(|e| e.context(format!("plugin {} failed", plugin_name(net))))?;
+            prev_result = Some(result);
+        }
+        let result = prev_result.ok_or(anyhow!("no plugins in list {}", list.name))?;
+
+        let info = CachedInfo {
+            netns: rt.netns.clone(),
+            cni_args: rt.args.clone(),
+            capability_args: rt.capability_args.clone(),
+            ..CachedInfo::new(
+                &list.name,
+                &rt.container_id,
+                &rt.if_name,
+                &list.bytes,
+                &result,
+            )?
+        };
+        self.cache(rt)
+            .set(&info)
+            .map_err(|e| e.context(format!("failed to cache result of {}", list.name)))?;
+        Ok(result)
+    }
+
+    /// Run CHECK for every plugin in order with the cached ADD result.
+    pub fn check_network_list(&self, list: &NetworkConfigList, rt: &RuntimeConf) -> CniResult<()> {
+        let cni_version = list_version(list);
+        if !version::greater_than_or_equal(cni_version, "0.4.0")? {
+            bail!(
+                "configuration version {:?} does not support the CHECK command",
+                cni_version
+            );
+        }
+        if list.disable_check {
+            return Ok(());
+        }
+
+        let cached = self.get_network_list_cached_result(list, rt)?;
+        for net in &list.plugins {
+            self.run(list, net, "CHECK", cached.as_ref(), rt, Map::new())?;
+        }
+        Ok(())
+    }
+
+    /// Run DEL for every plugin in reverse order, then drop the cached result.
+    pub fn del_network_list(&self, list: &NetworkConfigList, rt: &RuntimeConf) -> CniResult<()> {
+        let cni_version = list_version(list);
+        // Results were only passed to DEL from 0.4.0 on
+        let cached = if version::greater_than_or_equal(cni_version, "0.4.0")? {
+            self.get_network_list_cached_result(list, rt)?
+        } else {
+            None
+        };
+
+        for net in list.plugins.iter().rev() {
+            self.run(list, net, "DEL", cached.as_ref(), rt, Map::new())?;
+        }
+        self.cache(rt)
+            .delete(&list.name, &rt.container_id, &rt.if_name)
+    }
+
+    /// Run DEL for every cached attachment of the list that is not in
+    /// `valid_attachments`, then GC for every plugin, telling each which
+    /// attachments are still valid.
+    ///
+    /// Every stale attachment and every plugin gets its turn even if an
+    /// earlier one fails.
+    pub fn gc_network_list(
+        &self,
+        list: &NetworkConfigList,
+        valid_attachments: &[GcAttachment],
+    ) -> CniResult<()> {
+        if list.disable_gc {
+            return Ok(());
+        }
+
+        let valid = valid_attachments.iter().collect::<HashSet<_>>();
+        let mut errors = vec![];
+        for info in self.get_cached_attachments(None)? {
+            let attachment = GcAttachment {
+                container_id: info.container_id.clone(),
+                if_name: info.if_name.clone(),
+            };
+            if info.network_name != list.name || valid.contains(&attachment) {
+                continue;
+            }
+            let rt = RuntimeConf {
+                container_id: info.container_id,
+                netns: info.netns,
+                if_name: info.if_name,
+                args: info.cni_args,
+                capability_args: info.capability_args,
+                ..Default::default()
+            };
+            if let Err(e) = self.del_network_list(list, &rt) {
+                errors.push(format!(
+                    "failed to delete stale attachment {} {}: {:#}",
+                    rt.container_id, rt.if_name, e
+                ));
+            }
+        }
+
+        // Older plugins only get the DELs
+        if version::greater_than_or_equal(list_version(list), version::STATUS_GC_VERSION)? {
+            let mut extra = Map::new();
+            extra.insert(
+                "cni.dev/valid-attachments".into(),
+                serde_json::to_value(valid_attachments)?,
+            );
+            let rt = RuntimeConf::default();
+            for net in &list.plugins {
+                if let Err(e) = self.run(list, net, "GC", None, &rt, extra.clone()) {
+                    errors.push(format!("{:#}", e));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            bail!("{}", errors.join("; "));
+        }
+        Ok(())
+    }
+
+    /// Ask every plugin whether it is ready for ADD.
+    pub fn get_status(&self, list: &NetworkConfigList) -> CniResult<()> {
+        if !version::greater_than_or_equal(list_version(list), version::STATUS_GC_VERSION)? {
+            return Ok(());
+        }
+        let rt = RuntimeConf::default();
+        for net in &list.plugins {
+            self.run(list, net, "STATUS", None, &rt, Map::new())?;
+        }
+        Ok(())
+    }
+
+    /// The versions `plugin_type` supports, cached per binary.
+    pub fn get_version_info(&self, plugin_type: &str) -> CniResult<PluginInfo> {
+        let plugin_path = self.find_plugin(plugin_type, &RuntimeConf::default())?;
+        self.exec.plugin_info(&plugin_path, None)
+    }
+
+    pub fn get_network_list_cached_result(
+        &self,
+        list: &NetworkConfigList,
+        rt: &RuntimeConf,
+    ) -> CniResult<Option<VersionedResult>> {
+        self.cache(rt)
+            .get(&list.name, &rt.container_id, &rt.if_name)
+            .map_err(|e| {
+                e.context(format!(
+                    "failed to get network {:?} cached result",
+                    list.name
+                ))
+            })
+    }
+
+    /// The config and runtime settings the cached result was added with.
+    pub fn get_network_list_cached_config(
+        &self,
+        list: &NetworkConfigList,
+        rt: &RuntimeConf,
+    ) -> CniResult<Option<(Vec<u8>, RuntimeConf)>> {
+        let info = self
+            .cache(rt)
+            .get_info(&list.name, &rt.container_id, &rt.if_name)?;
+        Ok(info.map(|info| {
+            let rt = RuntimeConf {
+                container_id: info.container_id,
+                netns: info.netns,
+                if_name: info.if_name,
+                args: info.cni_args,
+                capability_args: info.capability_args,
+                ..rt.clone()
+            };
+            (info.config, rt)
+        }))
+    }
+
+    /// Every attachment with a cached result, or only those of
+    /// `container_id`, e.g. to work out what GC should keep.
+    pub fn get_cached_attachments(&self, container_id: Option<&str>) -> CniResult<Vec<CachedInfo>> {
+        ResultCache::new(&self.cache_dir).list(container_id)
+    }
+
+    fn add_network(
+        &self,
+        name: &str,
+        cni_version: &str,
+        net: &NetworkConfig,
+        prev_result: Option<&VersionedResult>,
+        rt: &RuntimeConf,
+    ) -> CniResult<VersionedResult> {
+        let plugin_path = self.find_plugin(plugin_name(net), rt)?;
+        let conf = net.build_plugin_conf(name, cni_version, prev_result, &rt.capability_args)?;
+        let output = exec_plugin_with_result(
+            self.exec.as_ref(),
+            &plugin_path,
+            &conf.bytes,
+            self.cni_args("ADD", rt),
+            rt.timeout,
+        )?;
+        VersionedResult::from_slice(&output)
+    }
+
+    fn run(
+        &self,
+        list: &NetworkConfigList,
+        net: &NetworkConfig,
+        command: &str,
+        prev_result: Option<&VersionedResult>,
+        rt: &RuntimeConf,
+        extra: Map<String, Value>,
+    ) -> CniResult<()> {
+        let plugin_path = self.find_plugin(plugin_name(net), rt)?;
+        let conf = net
+            .build_plugin_conf(
+                &list.name,
+                list_version(list),
+                prev_result,
+                &rt.capability_args,
+            )?
+            .inject(extra)?;
+        exec_plugin_with_result(
+            self.exec.as_ref(),
+            &plugin_path,
+            &conf.bytes,
+            self.cni_args(command, rt),
+            rt.timeout,
+        )
+        .map_err(|e| e.context(format!("plugin {} failed", plugin_name(net))))?;
+        Ok(())
+    }
+
+    fn plugin_path<'a>(&'a self, rt: &'a RuntimeConf) -> &'a [PathBuf] {
+        rt.cni_path.as_deref().unwrap_or(&self.path)
+    }
+
+    fn find_plugin(&self, plugin: &str, rt: &RuntimeConf) -> CniResult<PathBuf> {
+        self.exec.find_in_path(plugin, self.plugin_path(rt))
+    }
+
+    fn cni_args(&self, command: &str, rt: &RuntimeConf) -> CNIArgs {
+        CNIArgs {
+            command: command.to_string(),
+            containerid: rt.container_id.clone(),
+            netns: rt.netns.clone(),
+            args: rt.args.clone(),
+            ifname: rt.if_name.clone(),
+            path: join_path(self.plugin_path(rt)),
+        }
+    }
+
+    fn cache(&self, rt: &RuntimeConf) -> ResultCache {
+        ResultCache::new(rt.cache_dir.as_ref().unwrap_or(&self.cache_dir))
+    }
+}
+
+fn plugin_name(net: &NetworkConfig) -> &str {
+    &net.network.plugin
+}
+
+fn list_version(list: &NetworkConfigList) -> &str {
+    if list.cni_version.is_empty() {
+        version::DEFAULT_CONFIG_VERSION
+    } else {
+        &list.cni_version
+    }
+}
+
+fn join_path(path: &[PathBuf]) -> String {
+    path.iter()
+        .map(|it| it.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use cni_core::config::conf_list_from_bytes;
+    use cni_core::error::{CniError, ErrorCode};
+
+    use crate::exec::FakeExec;
+
+    use super::*;
+
+    // A plugin that appends its name and command to a log and answers ADD
+    // with an IP derived from the prevResult it got.
+    const FAKE_PLUGIN: &str = r#"#!/bin/sh
+stdin=$(cat)
+echo "$(basename $0) $CNI_COMMAND $CNI_CONTAINERID $CNI_ARGS $stdin" >> "$(dirname $0)/calls"
+case "$CNI_COMMAND" in
+ADD)
+    if echo "$stdin" | grep -q prevResult; then last=2; else last=1; fi
+    echo "{\"cniVersion\": \"1.0.0\", \"ips\": [{\"address\": \"10.0.0.$last/24\"}]}"
+    ;;
+VERSION)
+    echo '{"cniVersion": "1.1.0", "supportedVersions": ["1.0.0", "1.1.0"]}'
+    ;;
+esac
+"#;
+
+    fn setup() -> (tempfile::TempDir, PathBuf, CNIConfig) {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        for plugin in ["first", "second"] {
+            let path = bin.join(plugin);
+            std::fs::write(&path, FAKE_PLUGIN).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let config = CNIConfig::with_cache_dir(vec![bin.clone()], dir.path().join("cache"));
+        (dir, bin, config)
+    }
+
+    fn conf_list(cni_version: &str) -> NetworkConfigList {
+        conf_list_from_bytes(
+            format!(
+                r#"{{
+                    "cniVersion": "{}",
+                    "name": "testnet",
+                    "plugins": [
+                        {{"type": "first", "capabilities": {{"portMappings": true}}}},
+                        {{"type": "second"}}
+                    ]
+                }}"#,
+                cni_version
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn calls(bin: &Path) -> Vec<String> {
+        std::fs::read_to_string(bin.join("calls"))
+            .unwrap_or_default()
+            .lines()
+            .map(|it| it.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_network_list_lifecycle() {
+        let (_dir, bin, config) = setup();
+        let list = conf_list("1.1.0");
+        let rt = RuntimeConf {
+            container_id: "ctr1".into(),
+            netns: "/var/run/netns/ctr1".into(),
+            if_name: "eth0".into(),
+            args: vec![("K8S_POD_NAME".into(), "web".into())],
+            capability_args: HashMap::from([("portMappings".into(), json!([{"hostPort": 80}]))]),
+            ..Default::default()
+        };
+
+        let result = config.add_network_list(&list, &rt).unwrap();
+        let result = result.into_current().unwrap();
+        assert_eq!(result.ips[0].address.to_string(), "10.0.0.2/24");
+
+        let calls_after_add = calls(&bin);
+        assert_eq!(calls_after_add.len(), 2);
+        assert!(calls_after_add[0].starts_with("first ADD ctr1 K8S_POD_NAME=web {"));
+        assert!(calls_after_add[0].contains(r#""runtimeConfig":{"portMappings""#));
+        assert!(!calls_after_add[0].contains("prevResult"));
+        assert!(calls_after_add[1].starts_with("second ADD"));
+        assert!(calls_after_add[1].contains("10.0.0.1/24"));
+
+        let cached = config.get_network_list_cached_result(&list, &rt).unwrap();
+        assert!(cached.is_some());
+        let (cached_conf, cached_rt) = config
+            .get_network_list_cached_config(&list, &rt)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached_conf, list.bytes);
+        assert_eq!(cached_rt.netns, rt.netns);
+        assert_eq!(cached_rt.args, rt.args);
+        let attachments = config.get_cached_attachments(Some("ctr1")).unwrap();
+        assert_eq!(attachments[0].network_name, "testnet");
+        assert!(config
+            .get_cached_attachments(Some("ctr2"))
+            .unwrap()
+            .is_empty());
+
+        config.check_network_list(&list, &rt).unwrap();
+        config.del_network_list(&list, &rt).unwrap();
+        let calls = calls(&bin);
+        assert!(calls[2].starts_with("first CHECK") && calls[2].contains("10.0.0.2/24"));
+        assert!(calls[4].starts_with("second DEL") && calls[4].contains("10.0.0.2/24"));
+        assert!(calls[5].starts_with("first DEL"));
+        assert!(config
+            .get_network_list_cached_result(&list, &rt)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_status_and_gc() {
+        let (_dir, bin, config) = setup();
+        let valid = vec![GcAttachment {
+            container_id: "ctr1".into(),
+            if_name: "eth0".into(),
+        }];
+        config.gc_network_list(&conf_list("1.1.0"), &valid).unwrap();
+        config.get_status(&conf_list("1.1.0")).unwrap();
+        let calls_11 = calls(&bin);
+        assert_eq!(calls_11.len(), 4);
+        assert!(calls_11[0].starts_with("first GC"));
+        assert!(calls_11[0].contains(r#""cni.dev/valid-attachments":[{"containerID":"ctr1""#));
+        assert!(calls_11[3].starts_with("second STATUS"));
+
+        // Neither command exists before 1.1.0
+        config.gc_network_list(&conf_list("1.0.0"), &valid).unwrap();
+        config.get_status(&conf_list("1.0.0")).unwrap();
+        assert_eq!(calls(&bin).len(), 4);
+
+        let err = config.check_network_list(&conf_list("0.3.1"), &RuntimeConf::default());
+        assert!(err.unwrap_err().to_string().contains("does not support"));
+    }
+
+    #[test]
+    fn test_gc_deletes_stale_attachments() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec = Arc::new(
+            FakeExec::new()
+                .with_output("first", "ADD", json!({"cniVersion": "1.1.0"}))
+                .with_output("second", "ADD", json!({"cniVersion": "1.1.0"})),
+        );
+        let config = CNIConfig::with_cache_dir(vec![], dir.path()).with_exec(exec.clone());
+        let list = conf_list("1.1.0");
+        let mut other = conf_list("1.1.0");
+        other.name = "othernet".into();
+        let rt = |container_id: &str| RuntimeConf {
+            container_id: container_id.into(),
+            netns: format!("/var/run/netns/{}", container_id),
+            if_name: "eth0".into(),
+            ..Default::default()
+        };
+        config.add_network_list(&list, &rt("ctr1")).unwrap();
+        config.add_network_list(&list, &rt("ctr2")).unwrap();
+        config.add_network_list(&other, &rt("ctr3")).unwrap();
+        let adds = exec.calls().len();
+
+        let valid = vec![GcAttachment {
+            container_id: "ctr1".into(),
+            if_name: "eth0".into(),
+        }];
+        config.gc_network_list(&list, &valid).unwrap();
+
+        let calls = exec.calls()[adds..].to_vec();
+        let commands = calls
+            .iter()
+            .map(|it| format!("{} {}", it.plugin, it.command))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            vec!["second DEL", "first DEL", "first GC", "second GC"]
+        );
+        assert_eq!(calls[0].env["CNI_CONTAINERID"], "ctr2");
+        assert_eq!(calls[0].env["CNI_NETNS"], "/var/run/netns/ctr2");
+
+        let mut cached = config
+            .get_cached_attachments(None)
+            .unwrap()
+            .into_iter()
+            .map(|it| it.container_id)
+            .collect::<Vec<_>>();
+        cached.sort();
+        assert_eq!(cached, vec!["ctr1", "ctr3"]);
+    }
+
+    #[test]
+    fn test_add_stops_at_failing_plugin() {
+        let dir = tempfile::tempdir().unwrap();
+        let exec = Arc::new(
+            FakeExec::new()
+                .with_output("first", "ADD", json!({"cniVersion": "1.1.0"}))
+                .with_error(
+                    "second",
+                    "ADD",
+                    CniError::new(ErrorCode::TryAgainLater, "busy", ""),
+                ),
+        );
+        let config = CNIConfig::with_cache_dir(vec![], dir.path()).with_exec(exec.clone());
+        let list = conf_list("1.1.0");
+        let rt = RuntimeConf {
+            container_id: "ctr1".into(),
+            if_name: "eth0".into(),
+            ..Default::default()
+        };
+
+        let err = CniError::from(config.add_network_list(&list, &rt).unwrap_err());
+        assert_eq!(err.code, ErrorCode::TryAgainLater);
+        assert_eq!(exec.commands(), vec!["first ADD", "second ADD"]);
+        assert!(config
+            .get_network_list_cached_result(&list, &rt)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_cni_path_override() {
+        let (_dir, bin, _) = setup();
+        let config = CNIConfig::with_cache_dir(vec![], bin.join("cache"));
+        let list = conf_list("1.1.0");
+        let mut rt = RuntimeConf {
+            container_id: "ctr1".into(),
+            if_name: "eth0".into(),
+            ..Default::default()
+        };
+        let err = config.add_network_list(&list, &rt).unwrap_err();
+        assert!(err.is::<crate::PluginNotFound>());
+
+        rt.cni_path = Some(vec![bin.clone()]);
+        config.add_network_list(&list, &rt).unwrap();
+        config.del_network_list(&list, &rt).unwrap();
+        assert_eq!(calls(&bin).len(), 4);
+    }
+
+    #[test]
+    fn test_get_version_info() {
+        let (_dir, _, config) = setup();
+        let info = config.get_version_info("first").unwrap();
+        assert!(info.supports("1.1.0"));
+        assert!(config.get_version_info("missing").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Async versions of [`RawExec`](crate::RawExec) and the delegate helpers,
+//! for runtimes that drive many attachments at once on tokio.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use cni_core::result::VersionedResult;
+use cni_core::types::SuccessReply;
+use cni_core::version::PluginInfo;
+
+use crate::exec::{plugin_error, timed_out};
+use crate::probe;
+use crate::{delegate_common, with_prev_result, Args, DelegateArgs, PluginNotFound, RawExec};
+
+/// Runs plugins as tokio child processes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioExec {
+    /// Applies to runs that were not given a timeout of their own.
+    pub timeout: Option<Duration>,
+}
+
+impl TokioExec {
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+        }
+    }
+
+    /// Same as [`Exec::exec_plugin`](crate::Exec::exec_plugin). The plugin is
+    /// also killed if the returned future is dropped.
+    pub async fn exec_plugin(
+        &self,
+        plugin_path: &Path,
+        stdin_data: &[u8],
+        env: &HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Vec<u8>> {
+        debug!("plugin_path: {:?}", plugin_path);
+        debug!("env: {:?}", env);
+        debug!("stdin_data: {}", String::from_utf8_lossy(stdin_data));
+
+        let mut child = Command::new(plugin_path.as_os_str())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // The plugin gets `env` and nothing else from this process
+            .env_clear()
+            .envs(env)
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let stdin_data = stdin_data.to_vec();
+        let writer = async move {
+            match stdin.write_all(&stdin_data).await {
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+                other => other,
+            }
+        };
+        let run = async { tokio::try_join!(writer, child.wait_with_output()) };
+
+        let (_, output) = match timeout.or(self.timeout) {
+            // Dropping `run` drops the child, which kills it
+            Some(timeout) => tokio::time::timeout(timeout, run)
+                .await
+                .map_err(|_| timed_out(plugin_path, timeout))??,
+            None => run.await?,
+        };
+
+        if !output.stderr.is_empty() {
+            debug!(
+                "{} stderr: {}",
+                plugin_path.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        if !output.status.success() {
+            return Err(plugin_error(output.status, &output.stdout, &output.stderr).into());
+        }
+        Ok(output.stdout)
+    }
+
+    /// Async [`probe`](crate::probe::probe), sharing its cache.
+    pub async fn plugin_info(
+        &self,
+        plugin_path: &Path,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<PluginInfo> {
+        let mtime = probe::modified(plugin_path);
+        if let Some(info) = probe::cached(plugin_path, mtime) {
+            return Ok(info);
+        }
+        let (stdin, env) = probe::version_request();
+        let output = self.exec_plugin(plugin_path, &stdin, &env, timeout).await?;
+        let info: PluginInfo = serde_json::from_slice(&output)?;
+        probe::store(plugin_path, mtime, &info);
+        Ok(info)
+    }
+
+    async fn negotiate_version(
+        &self,
+        plugin_path: &Path,
+        net_conf: &[u8],
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let info = self.plugin_info(plugin_path, timeout).await?;
+        probe::with_negotiated_version(plugin_path, net_conf, &info)
+    }
+
+    async fn delegate(
+        &self,
+        plugin_path: &Path,
+        net_conf: &[u8],
+        command: &str,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let args = DelegateArgs {
+            command: command.to_string(),
+        };
+        self.exec_plugin(plugin_path, net_conf, &args.as_env(), timeout)
+            .await
+    }
+}
+
+/// Async [`delegate_add`](crate::delegate_add).
+pub async fn delegate_add(
+    plugin: &str,
+    net_conf: &[u8],
+    exec: &TokioExec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<SuccessReply> {
+    let plugin_path = delegate_common(plugin, &RawExec::default())?;
+    let net_conf = exec
+        .negotiate_version(&plugin_path, net_conf, timeout)
+        .await?;
+    let res = exec
+        .delegate(&plugin_path, &net_conf, "ADD", timeout)
+        .await?;
+    VersionedResult::from_slice(&res)?.into_current()
+}
+
+/// Async [`delegate_del`](crate::delegate_del).
+pub async fn delegate_del(
+    plugin: &str,
+    net_conf: &[u8],
+    ignore_not_found: bool,
+    exec: &TokioExec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let plugin_path = match delegate_common(plugin, &RawExec::default()) {
+        Ok(plugin_path) => plugin_path,
+        Err(e) if ignore_not_found && e.is::<PluginNotFound>() => {
+            warn!("{}, skipping DEL", e);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let net_conf = exec
+        .negotiate_version(&plugin_path, net_conf, timeout)
+        .await?;
+    exec.delegate(&plugin_path, &net_conf, "DEL", timeout)
+        .await?;
+    Ok(())
+}
+
+/// Async [`delegate_check`](crate::delegate_check).
+pub async fn delegate_check(
+    plugin: &str,
+    net_conf: &[u8],
+    prev_result: &SuccessReply,
+    exec: &TokioExec,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let plugin_path = delegate_common(plugin, &RawExec::default())?;
+    let net_conf = exec
+        .negotiate_version(&plugin_path, net_conf, timeout)
+        .await?;
+    let net_conf = with_prev_result(&net_conf, prev_result)?;
+    exec.delegate(&plugin_path, &net_conf, "CHECK", timeout)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cni_core::error::{CniError, ErrorCode};
+
+    use crate::exec::tests::script;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_exec_plugin() {
+        let path = script("echo", "cat");
+        let output = TokioExec::default()
+            .exec_plugin(&path, b"{}", &HashMap::new(), None)
+            .await
+            .unwrap();
+        assert_eq!(output, b"{}");
+
+        let path = script("busy", r#"echo '{"code": 11, "msg": "busy"}'; exit 1"#);
+        let err = TokioExec::default()
+            .exec_plugin(&path, b"{}", &HashMap::new(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(CniError::from(err).code, ErrorCode::TryAgainLater);
+    }
+
+    #[tokio::test]
+    async fn test_env_is_not_inherited() {
+        std::env::set_var("INVOKE_NONBLOCKING_SECRET", "secret");
+        let path = script("env", r#"echo "$INVOKE_NONBLOCKING_SECRET:$CNI_COMMAND""#);
+        let env = crate::EnvBuilder::new().set("CNI_COMMAND", "ADD").build();
+        let output = TokioExec::default()
+            .exec_plugin(&path, b"{}", &env, None)
+            .await
+            .unwrap();
+        assert_eq!(output, b":ADD\n");
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let path = script("hang", "sleep 10");
+        let started = std::time::Instant::now();
+        let err = TokioExec::with_timeout(Duration::from_millis(100))
+            .exec_plugin(&path, b"{}", &HashMap::new(), None)
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let err = CniError::from(err);
+        assert_eq!(err.code, ErrorCode::TryAgainLater);
+        assert!(err.msg.contains("timed out"));
+    }
+}
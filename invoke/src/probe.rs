@@ -0,0 +1,183 @@
+//! Asking plugins which spec versions they speak, and picking a `cniVersion`
+//! both sides understand before delegating to them.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use log::info;
+use serde_json::{json, Map, Value};
+
+use cni_core::error::{CniError, ErrorCode};
+use cni_core::prelude::CniResult;
+use cni_core::version::{self, PluginInfo};
+
+use crate::{Args, DelegateArgs, Exec};
+
+/// Answers to VERSION, keyed by plugin path and only valid for the binary
+/// with the recorded modification time.
+static CACHE: OnceLock<Mutex<HashMap<PathBuf, (SystemTime, PluginInfo)>>> = OnceLock::new();
+
+fn cache() -> &'static Mutex<HashMap<PathBuf, (SystemTime, PluginInfo)>> {
+    CACHE.get_or_init(Default::default)
+}
+
+pub(crate) fn modified(plugin_path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(plugin_path)
+        .and_then(|it| it.modified())
+        .ok()
+}
+
+pub(crate) fn cached(plugin_path: &Path, mtime: Option<SystemTime>) -> Option<PluginInfo> {
+    let mtime = mtime?;
+    match cache().lock().unwrap().get(plugin_path) {
+        Some((cached_mtime, info)) if *cached_mtime == mtime => Some(info.clone()),
+        _ => None,
+    }
+}
+
+pub(crate) fn store(plugin_path: &Path, mtime: Option<SystemTime>, info: &PluginInfo) {
+    if let Some(mtime) = mtime {
+        cache()
+            .lock()
+            .unwrap()
+            .insert(plugin_path.to_path_buf(), (mtime, info.clone()));
+    }
+}
+
+pub(crate) fn version_request() -> (Vec<u8>, HashMap<String, String>) {
+    let stdin = serde_json::to_vec(&json!({ "cniVersion": version::CURRENT })).unwrap();
+    let env = DelegateArgs {
+        command: "VERSION".to_string(),
+    }
+    .as_env();
+    (stdin, env)
+}
+
+/// Run VERSION on the plugin, or reuse the answer its binary gave before.
+pub fn probe<E: Exec + ?Sized>(
+    plugin_path: &Path,
+    exec: &E,
+    timeout: Option<Duration>,
+) -> CniResult<PluginInfo> {
+    let mtime = modified(plugin_path);
+    if let Some(info) = cached(plugin_path, mtime) {
+        return Ok(info);
+    }
+    let (stdin, env) = version_request();
+    let output = exec.exec_plugin(plugin_path, &stdin, &env, timeout)?;
+    let info = exec.decode(&output)?;
+    store(plugin_path, mtime, &info);
+    Ok(info)
+}
+
+/// Pick the version to run a plugin with for a config of version `requested`.
+///
+/// That is `requested` itself if the plugin supports it, otherwise the newest
+/// version the plugin supports below it. A plugin that only speaks newer
+/// versions fails with [`ErrorCode::IncompatibleCniVersion`].
+pub fn negotiate(info: &PluginInfo, requested: &str) -> CniResult<String> {
+    if info.supports(requested) {
+        return Ok(requested.to_string());
+    }
+    let requested_parsed = version::parse(requested)?;
+    let best = info
+        .supported_versions
+        .iter()
+        .filter_map(|it| Some((version::parse(it).ok()?, it)))
+        .filter(|(parsed, _)| *parsed < requested_parsed)
+        .max();
+    match best {
+        Some((_, version)) => Ok(version.clone()),
+        None => Err(CniError::new(
+            ErrorCode::IncompatibleCniVersion,
+            "incompatible CNI versions",
+            format!(
+                "config is {:?}, plugin supports {:?}",
+                requested, info.supported_versions
+            ),
+        )
+        .into()),
+    }
+}
+
+/// Rewrite `net_conf` to the `cniVersion` negotiated with the plugin.
+pub(crate) fn with_negotiated_version(
+    plugin_path: &Path,
+    net_conf: &[u8],
+    info: &PluginInfo,
+) -> CniResult<Vec<u8>> {
+    let requested = version::config_version(net_conf)?;
+    let negotiated = negotiate(info, &requested)
+        .map_err(|e| e.context(format!("plugin {} cannot be used", plugin_path.display())))?;
+    if negotiated == requested {
+        return Ok(net_conf.to_vec());
+    }
+    info!(
+        "{} does not support {}, using {}",
+        plugin_path.display(),
+        requested,
+        negotiated
+    );
+    let mut conf: Map<String, Value> = serde_json::from_slice(net_conf)?;
+    conf.insert("cniVersion".into(), negotiated.into());
+    Ok(serde_json::to_vec(&conf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::RawExec;
+
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let info = PluginInfo::new(&["0.3.1", "0.4.0", "1.0.0"]);
+        assert_eq!(negotiate(&info, "1.0.0").unwrap(), "1.0.0");
+        assert_eq!(negotiate(&info, "1.1.0").unwrap(), "1.0.0");
+        assert_eq!(negotiate(&info, "0.3.1").unwrap(), "0.3.1");
+
+        let err = CniError::from(negotiate(&info, "0.2.0").unwrap_err());
+        assert_eq!(err.code, ErrorCode::IncompatibleCniVersion);
+        assert!(err.details.contains(r#"config is "0.2.0""#));
+    }
+
+    #[test]
+    fn test_probe_is_cached_per_binary() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("plugin");
+        let write = |versions: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "#!/bin/sh\necho VERSION >> {}\necho '{{\"cniVersion\": \"1.0.0\", \"supportedVersions\": {}}}'\n",
+                    dir.join("calls").display(),
+                    versions
+                ),
+            )
+            .unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        write(r#"["0.4.0"]"#);
+
+        assert!(probe(&path, &RawExec::default(), None)
+            .unwrap()
+            .supports("0.4.0"));
+        assert!(probe(&path, &RawExec::default(), None)
+            .unwrap()
+            .supports("0.4.0"));
+        let calls = std::fs::read_to_string(dir.join("calls")).unwrap();
+        assert_eq!(calls.lines().count(), 1);
+
+        // A replaced binary is asked again
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        write(r#"["1.0.0"]"#);
+        assert!(probe(&path, &RawExec::default(), None)
+            .unwrap()
+            .supports("1.0.0"));
+    }
+}
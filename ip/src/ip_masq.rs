@@ -58,6 +58,23 @@ pub fn setup_ip_masq(ip: &IpNetwork, chain_name: &str) -> CniResult<()> {
     Ok(())
 }
 
+/// Undo [`setup_ip_masq`]. Rules and chains that are already gone are
+/// skipped, so this can run any number of times.
+pub fn teardown_ip_masq(ip: &IpNetwork, chain_name: &str) -> CniResult<()> {
+    let ipt = iptables::new(ip.is_ipv6()).unwrap();
+
+    let rule = format!("-s {} -j {}", ip.ip(), chain_name);
+    if wrap_err!(ipt.exists("nat", "POSTROUTING", &rule))? {
+        wrap_err!(ipt.delete("nat", "POSTROUTING", &rule))?;
+    }
+
+    if wrap_err!(ipt.chain_exists("nat", chain_name))? {
+        wrap_err!(ipt.flush_chain("nat", chain_name))?;
+        wrap_err!(ipt.delete_chain("nat", chain_name))?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::Ipv4Addr;
@@ -3,9 +3,11 @@ use std::net::{IpAddr, Ipv4Addr};
 use cni_core::prelude::CniResult;
 pub use ip_masq::*;
 pub use link::*;
+pub use spoof_check::*;
 
 mod ip_masq;
 mod link;
+mod spoof_check;
 
 pub fn next_ip(ip: &IpAddr) -> Option<IpAddr> {
     match ip {
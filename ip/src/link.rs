@@ -1,6 +1,9 @@
+use std::net::IpAddr;
+
 use anyhow::{anyhow, bail};
+use ipnetwork::IpNetwork;
 use log::info;
-use netlink_ng::nl_type::Veth;
+use netlink_ng::nl_type::{Veth, FAMILY_V4, FAMILY_V6};
 use netlink_ng::{Link, LinkAttrs, LinkKind, Namespace, TryAsLinkIndex};
 use netns_ng::Netns;
 use rand::random;
@@ -128,6 +131,45 @@ fn make_veth_pair(
     }
 }
 
+/// Delete the link `if_name` in the current netns and return the global
+/// unicast addresses it had. A missing link has no addresses.
+pub fn del_link_by_name_addr(if_name: &str) -> anyhow::Result<Vec<IpNetwork>> {
+    let link = match netlink_ng::link_by_name(if_name)? {
+        Some(link) => link,
+        None => return Ok(vec![]),
+    };
+
+    let mut addrs = netlink_ng::addr_list(link.as_index(), FAMILY_V4)?;
+    addrs.extend(netlink_ng::addr_list(link.as_index(), FAMILY_V6)?);
+
+    netlink_ng::link_del(link.as_index())?;
+
+    Ok(addrs
+        .into_iter()
+        .map(|it| it.ipnet)
+        .filter(|it| is_global_unicast(&it.ip()))
+        .collect())
+}
+
+fn is_global_unicast(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_broadcast())
+        }
+        // fe80::/10 is link local
+        IpAddr::V6(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.segments()[0] & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
 fn random_veth_name() -> String {
     let entropy: [u8; 4] = random();
     format!(
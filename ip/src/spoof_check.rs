@@ -0,0 +1,103 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::bail;
+
+use cni_core::prelude::CniResult;
+
+// table bridge CNI-0123 {
+//     chain prerouting {
+//         type filter hook prerouting priority -300; policy accept;
+//         iifname "veth0123" ether saddr != 0a:58:0a:f4:00:05 drop
+//     }
+// }
+fn rule(iface: &str, mac: &str) -> String {
+    format!(
+        "iifname \"{}\" ether saddr != {} drop",
+        iface,
+        mac.to_lowercase()
+    )
+}
+
+fn ruleset(table: &str, iface: &str, mac: &str) -> String {
+    // Adding and flushing first makes a repeated ADD replace the rule
+    format!(
+        "add table bridge {table}
+flush table bridge {table}
+table bridge {table} {{
+    chain prerouting {{
+        type filter hook prerouting priority -300; policy accept;
+        {rule}
+    }}
+}}
+",
+        table = table,
+        rule = rule(iface, mac)
+    )
+}
+
+fn nft(args: &[&str], stdin_data: &str) -> CniResult<String> {
+    let mut child = Command::new("nft")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin_data.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "nft {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Drop frames coming in from `iface` whose source MAC is not `mac`.
+///
+/// Each attachment gets its own bridge-family table, so it can be removed
+/// without looking at the others.
+pub fn setup_spoof_check(table: &str, iface: &str, mac: &str) -> CniResult<()> {
+    nft(&["-f", "-"], &ruleset(table, iface, mac))?;
+    Ok(())
+}
+
+/// Undo [`setup_spoof_check`]. A table that is already gone is skipped.
+pub fn teardown_spoof_check(table: &str) -> CniResult<()> {
+    // Adding the table first keeps the delete from failing
+    let script = format!(
+        "add table bridge {table}\ndelete table bridge {table}\n",
+        table = table
+    );
+    nft(&["-f", "-"], &script)?;
+    Ok(())
+}
+
+/// Whether the rule [`setup_spoof_check`] added is still in place.
+pub fn verify_spoof_check(table: &str, iface: &str, mac: &str) -> CniResult<bool> {
+    let ruleset = match nft(&["list", "table", "bridge", table], "") {
+        Ok(ruleset) => ruleset,
+        Err(_) => return Ok(false),
+    };
+    let rule = rule(iface, mac);
+    Ok(ruleset.lines().any(|it| it.trim() == rule))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruleset() {
+        let ruleset = ruleset("CNI-0123", "veth0123", "0A:58:0A:F4:00:05");
+        assert!(ruleset.starts_with("add table bridge CNI-0123\nflush table bridge CNI-0123\n"));
+        assert!(ruleset.contains("type filter hook prerouting priority -300; policy accept;"));
+        assert!(ruleset.contains("iifname \"veth0123\" ether saddr != 0a:58:0a:f4:00:05 drop"));
+    }
+}
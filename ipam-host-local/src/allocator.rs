@@ -246,6 +246,7 @@ mod tests {
 
     use ipnetwork::Ipv4Network;
 
+    use crate::disk::tests::temp_store;
     use crate::range::Range;
 
     use super::*;
@@ -304,8 +305,8 @@ mod tests {
             ..Default::default()
         }];
         range_set.canonicalize().unwrap();
-        std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-        let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+        let (_dir, store) = temp_store();
+        let store = Arc::new(store);
         let alloc = IpAllocator::new(range_set, store, 1);
 
         for i in 2..7 {
@@ -326,8 +327,8 @@ mod tests {
             ..Default::default()
         }];
         range_set.canonicalize().unwrap();
-        std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-        let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+        let (_dir, store) = temp_store();
+        let store = Arc::new(store);
         let alloc = IpAllocator::new(range_set, store, 1);
         let res = alloc.get("ID", "eth0".into(), None).unwrap();
         assert_eq!(res.address, "192.168.1.2/29".parse().unwrap());
@@ -345,8 +346,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 1);
             let mut iter = alloc.new_iter();
             assert_eq!(
@@ -377,8 +378,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 1);
             let mut iter = alloc.new_iter();
             alloc
@@ -416,8 +417,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 1);
             alloc
                 .store
@@ -458,8 +459,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 1);
             let ip = alloc.get("ID", "eth0".into(), None).unwrap();
             assert_eq!(ip.address.ip(), "10.0.0.2".parse::<IpAddr>().unwrap());
@@ -471,8 +472,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 1);
             let ip = alloc.get("ID", "eth0".into(), None).unwrap();
             assert_eq!(ip.address.ip(), "10.0.0.2".parse::<IpAddr>().unwrap());
@@ -484,8 +485,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 1);
             let ip = alloc.get("ID0", "eth0".into(), None).unwrap();
             let ip = alloc.get("ID1", "eth0".into(), None).unwrap();
@@ -497,8 +498,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 0);
             alloc
                 .store
@@ -513,8 +514,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 0);
             alloc
                 .store
@@ -537,8 +538,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 0);
             alloc
                 .store
@@ -558,8 +559,8 @@ mod tests {
                 ..Default::default()
             }];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 0);
             alloc
                 .store
@@ -598,8 +599,8 @@ mod tests {
                 },
             ];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 0);
             alloc
                 .store
@@ -625,8 +626,8 @@ mod tests {
                 },
             ];
             range_set.canonicalize().unwrap();
-            std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-            let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+            let (_dir, store) = temp_store();
+            let store = Arc::new(store);
             let alloc = IpAllocator::new(range_set, store, 0);
             alloc
                 .store
@@ -645,8 +646,8 @@ mod tests {
             ..Default::default()
         }];
         range_set.canonicalize().unwrap();
-        std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-        let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+        let (_dir, store) = temp_store();
+        let store = Arc::new(store);
         let alloc = IpAllocator::new(range_set, store, 0);
         for i in 2..7 {
             let ip = alloc.get(&format!("ID{}", i), "eth0".into(), None).unwrap();
@@ -663,8 +664,8 @@ mod tests {
             ..Default::default()
         }];
         range_set.canonicalize().unwrap();
-        std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-        let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+        let (_dir, store) = temp_store();
+        let store = Arc::new(store);
         let alloc = IpAllocator::new(range_set, store, 0);
         let ip = alloc
             .get("ID", "eth0".into(), Some("192.168.1.5".parse().unwrap()))
@@ -683,8 +684,8 @@ mod tests {
             ..Default::default()
         }];
         range_set.canonicalize().unwrap();
-        std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-        let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+        let (_dir, store) = temp_store();
+        let store = Arc::new(store);
         let alloc = IpAllocator::new(range_set, store, 0);
         let result = alloc.get("ID", "eth0".into(), Some("192.168.1.5".parse().unwrap()));
         assert!(result.is_err());
@@ -695,8 +696,8 @@ mod tests {
             ..Default::default()
         }];
         range_set.canonicalize().unwrap();
-        std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-        let store = Arc::new(Store::new(Some("/tmp/ipam".into())).unwrap());
+        let (_dir, store) = temp_store();
+        let store = Arc::new(store);
         let alloc = IpAllocator::new(range_set, store, 0);
         let result = alloc.get("ID", "eth0".into(), Some("192.168.1.2".parse().unwrap()));
         assert!(result.is_err());
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::fs::{DirBuilder, File, OpenOptions};
 use std::io::Write;
 use std::net::IpAddr;
@@ -7,6 +8,7 @@ use std::path::PathBuf;
 
 const LINE_BREAK: &str = "\r\n";
 const LAST_IPFILE_PREFIX: &str = "last_reserved_ip_";
+const DEFAULT_DATA_DIR: &str = "/var/lib/cni/networks";
 
 // Store is a simple disk-backed store that creates one file per IP
 // address in a given directory. The contents of the file are the container ID.
@@ -17,7 +19,7 @@ pub struct Store {
 
 impl Store {
     pub fn new(data_dir: Option<String>) -> anyhow::Result<Self> {
-        let data_dir = data_dir.unwrap_or("/var/lib/cni/networks".into());
+        let data_dir = data_dir.unwrap_or(DEFAULT_DATA_DIR.into());
         DirBuilder::new()
             .recursive(true)
             .mode(0o755)
@@ -27,6 +29,14 @@ impl Store {
         let store = Store { dir: file, path };
         Ok(store)
     }
+
+    // ForNetwork opens the store of one network, <dataDir>/<network> like Go
+    // host-local, so networks sharing a dataDir never touch each other's leases
+    pub fn for_network(data_dir: Option<String>, network: &str) -> anyhow::Result<Self> {
+        let data_dir = PathBuf::from(data_dir.unwrap_or(DEFAULT_DATA_DIR.into())).join(network);
+        Self::new(Some(data_dir.to_string_lossy().to_string()))
+    }
+
     // GetByID returns the IPs which have been allocated to the specific ID
     pub fn get_by_id(&self, id: &str, ifname: &str) -> anyhow::Result<Vec<IpAddr>> {
         let text_match = format!("{}{}{}", id, LINE_BREAK, ifname);
@@ -106,6 +116,40 @@ impl Store {
         }
         Ok(found)
     }
+
+    // ReleaseAllExcept releases every IP whose container ID and ifname are not
+    // in keep, returning the released IPs
+    pub fn release_all_except(
+        &self,
+        keep: &HashSet<(String, String)>,
+    ) -> anyhow::Result<Vec<IpAddr>> {
+        let mut released = vec![];
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if !entry.metadata()?.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            // Skips the last reserved IP files
+            let ip = match path.file_name().and_then(|it| it.to_str()) {
+                Some(name) => match name.parse::<IpAddr>() {
+                    Ok(ip) => ip,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            let data = std::fs::read_to_string(&path)?;
+            let (id, ifname) = match data.trim().split_once(LINE_BREAK) {
+                Some(it) => it,
+                None => continue,
+            };
+            if !keep.contains(&(id.to_string(), ifname.to_string())) {
+                std::fs::remove_file(&path)?;
+                released.push(ip);
+            }
+        }
+        Ok(released)
+    }
 }
 
 pub struct FileLock {
@@ -144,11 +188,18 @@ impl FileLock {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::thread::sleep;
 
     use super::*;
 
+    /// A store in a fresh directory that is removed with the returned guard.
+    pub(crate) fn temp_store() -> (tempfile::TempDir, Store) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(Some(dir.path().to_string_lossy().to_string())).unwrap();
+        (dir, store)
+    }
+
     #[test]
     fn test_file_lock() -> anyhow::Result<()> {
         let store = Store {
@@ -166,8 +217,7 @@ mod tests {
 
     #[test]
     fn test_release() {
-        std::fs::remove_dir_all("/tmp/ipam").unwrap_or_default();
-        let store = Store::new(Some("/tmp/ipam".into())).unwrap();
+        let (_dir, store) = temp_store();
         let _ = store.new_lock();
         store
             .reserve("id#0", "eth0", "192.168.1.2".parse().unwrap(), "1")
@@ -175,4 +225,35 @@ mod tests {
         sleep(std::time::Duration::from_secs(10));
         store.release_by_id("id#0", "eth0").unwrap();
     }
+
+    #[test]
+    fn test_release_all_except() {
+        let (_dir, store) = temp_store();
+        let _lock = store.new_lock().unwrap();
+        store
+            .reserve("id#0", "eth0", "192.168.1.2".parse().unwrap(), "0")
+            .unwrap();
+        store
+            .reserve("id#1", "eth0", "192.168.1.3".parse().unwrap(), "0")
+            .unwrap();
+        store
+            .reserve("id#0", "net1", "192.168.1.4".parse().unwrap(), "0")
+            .unwrap();
+
+        let keep = HashSet::from([("id#0".to_string(), "eth0".to_string())]);
+        let mut released = store.release_all_except(&keep).unwrap();
+        released.sort();
+        assert_eq!(
+            released,
+            vec![
+                "192.168.1.3".parse::<IpAddr>().unwrap(),
+                "192.168.1.4".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            store.get_by_id("id#0", "eth0").unwrap(),
+            vec!["192.168.1.2".parse::<IpAddr>().unwrap()]
+        );
+        assert!(store.last_reserved_ip("0").is_some());
+    }
 }
@@ -1,15 +1,16 @@
-use std::io::{stdin, stdout};
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use anyhow::bail;
 
 use cni_core::skel;
-use cni_core::skel::CmdArgs;
-use cni_core::types::ExecResult;
+use cni_core::skel::{CmdArgs, CniPlugin};
+use cni_core::types::SuccessReply;
+use cni_core::version::PluginInfo;
 
 use crate::allocator::IpAllocator;
 use crate::config::{IPAMConfig, Net};
-use crate::disk::Store;
+use crate::disk::{FileLockExt, Store};
 use crate::range_set::RangeSetExt;
 
 mod allocator;
@@ -20,18 +21,35 @@ mod range_set;
 
 // host-local IPAM allocates IPv4 and IPv6 addresses out of a specified address range.
 // Optionally, it can include a DNS configuration from a resolv.conf file on the host.
-fn main() -> anyhow::Result<()> {
-    // logger::init("ipam_host_local.log")?;
-    skel::plugin_main(
-        |args| cmd_add(args),
-        |args| cmd_add(args),
-        |args| cmd_add(args),
-    )?;
-    Ok(())
+fn main() {
+    skel::plugin_main(HostLocalIpam, PluginInfo::all());
+}
+
+struct HostLocalIpam;
+
+impl CniPlugin for HostLocalIpam {
+    const NAME: &'static str = "host-local";
+    type Config = Net;
+    type Output = SuccessReply;
+
+    fn add(&self, args: &CmdArgs, config: Net) -> anyhow::Result<SuccessReply> {
+        cmd_add(args, config)
+    }
+
+    fn del(&self, args: &CmdArgs, config: Net) -> anyhow::Result<()> {
+        cmd_del(args, config)
+    }
+
+    fn check(&self, args: &CmdArgs, config: Net) -> anyhow::Result<()> {
+        cmd_check(args, config)
+    }
+
+    fn gc(&self, args: &CmdArgs, config: Net) -> anyhow::Result<()> {
+        cmd_gc(args, config)
+    }
 }
 
-fn load_ipam_config() -> anyhow::Result<(IPAMConfig, String)> {
-    let mut n: Net = serde_json::from_reader(stdin())?;
+fn load_ipam_config(mut n: Net) -> anyhow::Result<(IPAMConfig, String)> {
     // todo add resolv.conf
     if n.ipam.ranges.is_empty() {
         bail!("no IP ranges specified")
@@ -53,14 +71,21 @@ fn load_ipam_config() -> anyhow::Result<(IPAMConfig, String)> {
     Ok((n.ipam, n.cni_version.clone()))
 }
 
-fn cmd_add(cmd_args: CmdArgs) -> anyhow::Result<()> {
-    let (ipam_config, cni_version) = load_ipam_config()?;
-    let store = Arc::new(Store::new(ipam_config.data_dir)?);
+fn open_store(ipam_config: &IPAMConfig) -> anyhow::Result<Store> {
+    Store::for_network(
+        ipam_config.data_dir.clone(),
+        ipam_config.name.as_deref().unwrap_or_default(),
+    )
+}
+
+fn cmd_add(cmd_args: &CmdArgs, net: Net) -> anyhow::Result<SuccessReply> {
+    let (ipam_config, cni_version) = load_ipam_config(net)?;
+    let store = Arc::new(open_store(&ipam_config)?);
 
     // let requested_ips: HashMap<String, IpAddr> = HashMap::new();
 
     let mut allocators: Vec<IpAllocator> = vec![];
-    let mut exec_result = ExecResult::default();
+    let mut result = SuccessReply::default();
 
     let mut ips = vec![];
     for (idx, rangeset) in ipam_config.ranges.into_iter().enumerate() {
@@ -81,9 +106,103 @@ fn cmd_add(cmd_args: CmdArgs) -> anyhow::Result<()> {
         allocators.push(allocator);
     }
 
-    exec_result.cni_version = Some(cni_version);
-    exec_result.ips = Some(ips);
-    exec_result.routes = ipam_config.routes;
-    serde_json::to_writer(stdout(), &exec_result).expect("writing to stdout should not fail");
+    result.cni_version = cni_version;
+    result.ips = ips;
+    result.routes = ipam_config.routes.unwrap_or_default();
+    Ok(result)
+}
+
+fn cmd_del(cmd_args: &CmdArgs, net: Net) -> anyhow::Result<()> {
+    let (ipam_config, _) = load_ipam_config(net)?;
+    let store = Arc::new(open_store(&ipam_config)?);
+
+    // Loop through all ranges, releasing all IPs, even if an error occurs
+    let mut errors = vec![];
+    for (idx, rangeset) in ipam_config.ranges.into_iter().enumerate() {
+        let allocator = IpAllocator::new(rangeset, store.clone(), idx);
+        if let Err(e) = allocator.release(&cmd_args.container_id, &cmd_args.if_name) {
+            errors.push(e.to_string());
+        }
+    }
+    if !errors.is_empty() {
+        bail!("{}", errors.join(";"));
+    }
     Ok(())
 }
+
+fn cmd_check(cmd_args: &CmdArgs, net: Net) -> anyhow::Result<()> {
+    let (ipam_config, _) = load_ipam_config(net)?;
+    let store = open_store(&ipam_config)?;
+    let _lock = store.new_lock()?;
+
+    let ips = store.get_by_id(&cmd_args.container_id, &cmd_args.if_name)?;
+    if ips.is_empty() {
+        bail!(
+            "host-local: Failed to find address added by container {}",
+            cmd_args.container_id
+        );
+    }
+    Ok(())
+}
+
+// Release every lease whose attachment the runtime no longer knows about
+fn cmd_gc(cmd_args: &CmdArgs, net: Net) -> anyhow::Result<()> {
+    let (ipam_config, _) = load_ipam_config(net)?;
+    let store = open_store(&ipam_config)?;
+    let _lock = store.new_lock()?;
+
+    let valid = cmd_args
+        .valid_attachments
+        .iter()
+        .map(|it| (it.container_id.clone(), it.if_name.clone()))
+        .collect::<HashSet<_>>();
+    for ip in store.release_all_except(&valid)? {
+        log::info!("host-local: released stale lease {}", ip);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn net(name: &str, data_dir: &str) -> Net {
+        serde_json::from_value(json!({
+            "cniVersion": "1.0.0",
+            "name": name,
+            "ipam": {
+                "type": "host-local",
+                "dataDir": data_dir,
+                "ranges": [[{"subnet": "10.1.2.0/24"}]]
+            }
+        }))
+        .unwrap()
+    }
+
+    fn cmd_args(container_id: &str) -> CmdArgs {
+        CmdArgs {
+            container_id: container_id.to_string(),
+            netns: String::new(),
+            if_name: "eth0".to_string(),
+            args: String::new(),
+            path: String::new(),
+            stdin_data: vec![],
+            valid_attachments: vec![],
+        }
+    }
+
+    #[test]
+    fn test_gc_keeps_other_networks() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+
+        cmd_add(&cmd_args("ctr1"), net("net-a", data_dir)).unwrap();
+        cmd_add(&cmd_args("ctr2"), net("net-b", data_dir)).unwrap();
+
+        cmd_gc(&cmd_args(""), net("net-a", data_dir)).unwrap();
+        assert!(cmd_check(&cmd_args("ctr1"), net("net-a", data_dir)).is_err());
+        cmd_check(&cmd_args("ctr2"), net("net-b", data_dir)).unwrap();
+    }
+}
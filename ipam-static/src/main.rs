@@ -2,17 +2,17 @@
 extern crate log;
 extern crate simplelog;
 
-use std::io::{stdin, stdout};
-
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use serde::{Deserialize, Serialize};
 
 use cni_core::prelude::*;
-use cni_core::skel::CmdArgs;
-use cni_core::types::{IPAMConfig, SuccessReply};
-use cni_core::{logger, skel};
+use cni_core::skel;
+use cni_core::skel::{CmdArgs, CniPlugin};
+use cni_core::types::{IPAMConfig, Ip, SuccessReply};
+use cni_core::version::PluginInfo;
+use cni_core::{args, version};
 
-use crate::types::NetConf;
+use crate::types::{NetConf, StaticEnvArgs};
 
 mod types;
 
@@ -47,48 +47,97 @@ mod types;
 // 	}
 // }
 
-fn main() -> CniResult<()> {
-    logger::init("ipam_static.log")?;
-    skel::plugin_main(
-        |args| cmd_add(args),
-        |args| cmd_add(args),
-        |args| cmd_add(args),
-    )?;
-    Ok(())
+fn main() {
+    skel::plugin_main(StaticIpam, PluginInfo::all());
 }
 
-fn cmd_add(cmd_args: CmdArgs) -> CniResult<()> {
-    info!("cmd_args: {:?}", cmd_args);
-    let mut ipam = load_ipam_conf(&cmd_args.args)?;
-    let ipam_type = ipam.plugin;
-    if ipam_type != "static" {
-        panic!("only support static ipam");
+struct StaticIpam;
+
+impl CniPlugin for StaticIpam {
+    const NAME: &'static str = "static";
+    type Config = NetConf;
+    type Output = SuccessReply;
+
+    fn add(&self, args: &CmdArgs, config: NetConf) -> CniResult<SuccessReply> {
+        cmd_add(args, config)
     }
+
+    fn del(&self, args: &CmdArgs, config: NetConf) -> CniResult<()> {
+        cmd_del(args, config)
+    }
+
+    fn check(&self, args: &CmdArgs, config: NetConf) -> CniResult<()> {
+        cmd_check(args, config)
+    }
+}
+
+fn cmd_add(cmd_args: &CmdArgs, net_config: NetConf) -> CniResult<SuccessReply> {
+    info!("cmd_args: {:?}", cmd_args);
+    let mut ipam = load_ipam_conf(net_config, &cmd_args.args)?;
     let result = SuccessReply {
-        cni_version: "1.0.0".to_string(),
+        cni_version: version::CURRENT.to_string(),
         interfaces: vec![],
         ips: ipam.addresses.take().unwrap_or_default(),
         routes: ipam.routes.take().unwrap_or_default(),
         dns: ipam.dns.take().unwrap_or_default(),
         specific: Default::default(),
     };
+    Ok(result)
+}
 
-    serde_json::to_writer(stdout(), &result).expect("writing to stdout should not fail");
+// Nothing to release, static addresses are never allocated anywhere.
+fn cmd_del(cmd_args: &CmdArgs, net_config: NetConf) -> CniResult<()> {
+    load_ipam_conf(net_config, &cmd_args.args)?;
     Ok(())
 }
 
-fn cmd_del(cmd_args: CmdArgs) -> CniResult<()> {
-    todo!()
-}
+fn cmd_check(cmd_args: &CmdArgs, mut net_config: NetConf) -> CniResult<()> {
+    let prev_result = net_config
+        .prev_result
+        .take()
+        .ok_or(anyhow!("Required prevResult missing"))?;
+    let ipam = load_ipam_conf(net_config, &cmd_args.args)?;
 
-fn cmd_check(cmd_args: CmdArgs) -> CniResult<()> {
-    todo!()
+    // Each configured address must have made it into the previous result
+    for address in ipam.addresses.unwrap_or_default() {
+        if !prev_result
+            .ips
+            .iter()
+            .any(|ip| ip.address == address.address)
+        {
+            bail!(
+                "static: Failed to match addr {} on interface",
+                address.address
+            );
+        }
+    }
+    Ok(())
 }
 
-fn load_ipam_conf(env_args: &str) -> CniResult<IPAMConfig> {
-    let net_config: NetConf = serde_json::from_reader(stdin()).unwrap();
-    let ipam = net_config
+fn load_ipam_conf(net_config: NetConf, env_args: &str) -> CniResult<IPAMConfig> {
+    let mut ipam = net_config
         .ipam
         .ok_or(anyhow!("IPAM config missing 'ipam' key"))?;
+    if ipam.plugin != "static" {
+        bail!("only support static ipam, got: {}", ipam.plugin);
+    }
+
+    if !env_args.is_empty() {
+        let env_args: StaticEnvArgs = args::load_args(env_args)?;
+        let addresses = ipam.addresses.get_or_insert_with(Vec::new);
+        addresses.extend(env_args.ip.into_iter().map(|address| Ip {
+            address,
+            gateway: None,
+            interface: None,
+        }));
+        // A gateway belongs to every address whose subnet contains it
+        for gateway in env_args.gateway {
+            for address in addresses.iter_mut() {
+                if address.address.contains(gateway) {
+                    address.gateway = Some(gateway);
+                }
+            }
+        }
+    }
     Ok(ipam)
 }
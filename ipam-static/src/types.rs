@@ -1,5 +1,11 @@
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
 
+use cni_core::args::{parse_value, CommonArgs, LoadArgs};
+use cni_core::prelude::CniResult;
+use cni_core::result::PrevResult;
 use cni_core::types::{IPAMArgs, IPAMConfig};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -17,6 +23,8 @@ pub struct NetConf {
     pub runtime: Option<RuntimeConfig>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub args: Option<IPAMArgs>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_result: Option<PrevResult>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -24,3 +32,55 @@ pub struct NetConf {
 pub struct RuntimeConfig {
     ips: Option<Vec<String>>,
 }
+
+// CNI_ARGS understood by static, e.g. IP=10.10.0.1/24,10.10.0.2/24;GATEWAY=10.10.0.254
+#[derive(Clone, Debug, Default)]
+pub struct StaticEnvArgs {
+    pub common: CommonArgs,
+    pub ip: Vec<IpNetwork>,
+    pub gateway: Vec<IpAddr>,
+}
+
+impl LoadArgs for StaticEnvArgs {
+    fn set_arg(&mut self, key: &str, value: &str) -> CniResult<bool> {
+        match key {
+            "IP" => {
+                self.ip = value
+                    .split(',')
+                    .map(|it| parse_value(key, it))
+                    .collect::<CniResult<_>>()?
+            }
+            "GATEWAY" => {
+                self.gateway = value
+                    .split(',')
+                    .map(|it| parse_value(key, it))
+                    .collect::<CniResult<_>>()?
+            }
+            _ => return self.common.set_arg(key, value),
+        }
+        Ok(true)
+    }
+
+    fn ignore_unknown(&self) -> bool {
+        self.common.ignore_unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cni_core::args::load_args;
+
+    use super::*;
+
+    #[test]
+    fn test_load_env_args() {
+        let args: StaticEnvArgs =
+            load_args("IP=10.10.0.1/24,10.10.1.1/24;GATEWAY=10.10.0.254;IgnoreUnknown=1;X=y")
+                .unwrap();
+        assert_eq!(args.ip.len(), 2);
+        assert_eq!(args.gateway, vec!["10.10.0.254".parse::<IpAddr>().unwrap()]);
+        assert!(load_args::<StaticEnvArgs>("IP=10.10.0.1").is_ok());
+        assert!(load_args::<StaticEnvArgs>("IP=bogus").is_err());
+        assert!(load_args::<StaticEnvArgs>("X=y").is_err());
+    }
+}
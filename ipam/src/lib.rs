@@ -1,15 +1,13 @@
 use anyhow::anyhow;
 use netlink_ng::Addr;
 
-use cni_core::types::ExecResult;
+use cni_core::types::SuccessReply;
 
-pub fn config_interface(if_name: &str, exec_result: &ExecResult) -> anyhow::Result<()> {
+pub fn config_interface(if_name: &str, result: &SuccessReply) -> anyhow::Result<()> {
     let link = netlink_ng::link_by_name(if_name)?;
     let link = link.ok_or(anyhow!("link not found"))?;
 
-    let ips = exec_result.ips.as_ref().ok_or(anyhow!("ips not found"))?;
-
-    for ip in ips {
+    for ip in &result.ips {
         if ip.interface.is_none() {
             continue;
         }
@@ -25,16 +23,14 @@ pub fn config_interface(if_name: &str, exec_result: &ExecResult) -> anyhow::Resu
 
     netlink_ng::link_set_up(&link)?;
 
-    if let Some(routes) = &exec_result.routes {
-        for route in routes {
-            let route = netlink_ng::types::Route {
-                dst: Some(route.dst.clone()),
-                link_index: link.attrs().index,
-                gw: route.gw.clone(),
-                ..Default::default()
-            };
-            netlink_ng::route_add_ecmp(&route)?;
-        }
+    for route in &result.routes {
+        let route = netlink_ng::types::Route {
+            dst: Some(route.dst.clone()),
+            link_index: link.attrs().index,
+            gw: route.gw.clone(),
+            ..Default::default()
+        };
+        netlink_ng::route_add_ecmp(&route)?;
     }
 
     Ok(())